@@ -0,0 +1,152 @@
+//! Bidirectional text, using the Unicode Bidirectional Algorithm (UAX #9).
+
+use unicode_bidi::{bidi_class, get_base_direction, BidiClass, BidiInfo, Direction, Level};
+
+use crate::TextDirection;
+
+/// Resolve the embedding level of each byte of the text.
+///
+/// The text is treated as a single line, so rule L1 is applied at the end of
+/// each paragraph.
+pub(crate) fn bidi_levels(text: &str, direction: TextDirection) -> Vec<u8> {
+    let default_para_level = match direction {
+        TextDirection::Auto => None,
+        TextDirection::LeftToRight => Some(Level::ltr()),
+        TextDirection::RightToLeft => Some(Level::rtl()),
+    };
+    let bidi_info = BidiInfo::new(text, default_para_level);
+    let mut levels = Vec::with_capacity(text.len());
+    for para in &bidi_info.paragraphs {
+        let para_levels = bidi_info.reordered_levels(para, para.range.clone());
+        levels.extend(
+            para_levels[para.range.clone()]
+                .iter()
+                .map(|level| level.number()),
+        );
+    }
+    levels
+}
+
+/// The embedding level of the paragraph at the start of the text, as chosen by
+/// `bidi_levels` (rules P2 and P3).
+pub(crate) fn paragraph_level(text: &str, direction: TextDirection) -> u8 {
+    match direction {
+        TextDirection::LeftToRight => 0,
+        TextDirection::RightToLeft => 1,
+        TextDirection::Auto => match get_base_direction(text) {
+            Direction::Rtl => 1,
+            _ => 0,
+        },
+    }
+}
+
+/// Whether the character takes the paragraph level when it is in the
+/// whitespace at the end of a line (rule L1). This includes the segment and
+/// paragraph separators that end the whitespace.
+pub(crate) fn is_line_end_whitespace(c: char) -> bool {
+    matches!(
+        bidi_class(c),
+        BidiClass::WS
+            | BidiClass::S
+            | BidiClass::B
+            | BidiClass::LRI
+            | BidiClass::RLI
+            | BidiClass::FSI
+            | BidiClass::PDI
+    )
+}
+
+/// Reorder runs from logical to visual order (rule L2).
+///
+/// The result is the indices of the runs, in left to right order.
+pub(crate) fn visual_order(levels: &[u8]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..levels.len()).collect();
+    let max_level = levels.iter().copied().max().unwrap_or(0);
+    let min_odd_level = levels.iter().copied().min().unwrap_or(0) | 1;
+    for level in (min_odd_level..=max_level).rev() {
+        let mut i = 0;
+        while i < order.len() {
+            if levels[order[i]] >= level {
+                let start = i;
+                while i < order.len() && levels[order[i]] >= level {
+                    i += 1;
+                }
+                order[start..i].reverse();
+            } else {
+                i += 1;
+            }
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bidi_levels, is_line_end_whitespace, paragraph_level, visual_order};
+    use crate::TextDirection;
+
+    #[test]
+    fn visual_order_ltr() {
+        assert_eq!(visual_order(&[0, 0, 0]), vec![0, 1, 2]);
+        assert_eq!(visual_order(&[]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn visual_order_reverses_rtl_runs() {
+        assert_eq!(visual_order(&[1, 1, 1]), vec![2, 1, 0]);
+        assert_eq!(visual_order(&[0, 1, 1, 0]), vec![0, 2, 1, 3]);
+    }
+
+    #[test]
+    fn visual_order_nested_levels() {
+        // Numbers (level 2) inside right-to-left text keep their order.
+        assert_eq!(visual_order(&[1, 2, 2, 1]), vec![3, 1, 2, 0]);
+        // From the highest level down to the lowest odd level.
+        assert_eq!(visual_order(&[0, 1, 2, 3, 0]), vec![0, 2, 3, 1, 4]);
+        assert_eq!(visual_order(&[2, 2, 1, 2]), vec![3, 2, 0, 1]);
+    }
+
+    #[test]
+    fn levels_per_byte() {
+        let text = "ab שלום";
+        let levels = bidi_levels(text, TextDirection::Auto);
+        assert_eq!(levels.len(), text.len());
+        assert_eq!(&levels[..3], &[0, 0, 0]);
+        assert!(levels[3..].iter().all(|&level| level == 1));
+    }
+
+    #[test]
+    fn levels_follow_base_direction() {
+        let levels = bidi_levels("שלום ab", TextDirection::Auto);
+        assert_eq!(levels[0], 1);
+        assert_eq!(*levels.last().unwrap(), 2);
+        let levels = bidi_levels("ab", TextDirection::RightToLeft);
+        assert_eq!(levels, vec![2, 2]);
+    }
+
+    #[test]
+    fn trailing_whitespace_takes_paragraph_level() {
+        // Rule L1: whitespace at the end of the line resets to the paragraph
+        // level.
+        let levels = bidi_levels("ab שלום ", TextDirection::LeftToRight);
+        assert_eq!(*levels.last().unwrap(), 0);
+    }
+
+    #[test]
+    fn paragraph_level_from_first_strong() {
+        assert_eq!(paragraph_level("123 שלום ab", TextDirection::Auto), 1);
+        assert_eq!(paragraph_level("ab שלום", TextDirection::Auto), 0);
+        assert_eq!(paragraph_level("123", TextDirection::Auto), 0);
+        assert_eq!(paragraph_level("ab", TextDirection::RightToLeft), 1);
+    }
+
+    #[test]
+    fn line_end_whitespace() {
+        assert!(is_line_end_whitespace(' '));
+        assert!(is_line_end_whitespace('\t'));
+        assert!(is_line_end_whitespace('\n'));
+        assert!(is_line_end_whitespace('\u{2069}'));
+        assert!(!is_line_end_whitespace('a'));
+        assert!(!is_line_end_whitespace('\u{a0}'));
+    }
+}
@@ -0,0 +1,98 @@
+//! Bounding boxes of laid out text.
+
+use pathfinder_geometry::rect::RectF;
+use pathfinder_geometry::vector::{vec2f, Vector2F};
+
+use crate::hb_layout::fragment_ink_bounds;
+use crate::metrics::font_metrics;
+use crate::session::LayoutFragment;
+use crate::{TextStyle, WritingMode};
+
+/// The bounding boxes of a layout.
+///
+/// Coordinates are the same as for glyph offsets: y is up, and the origin
+/// is the start of the layout on the baseline.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TextBounds {
+    /// The box spanned by the advance and the largest ascent and descent of
+    /// the fonts used. In vertical text, this is centered on the line.
+    pub logical: RectF,
+    /// The box enclosing the glyph outlines, including faux bold and italic.
+    /// This is an empty box at the origin when no glyph has any ink.
+    pub ink: RectF,
+}
+
+/// Compute the bounds of fragments placed one after another in visual order.
+/// The writing mode is that of the first style.
+pub(crate) fn fragments_bounds(
+    fragments: &[LayoutFragment],
+    visual_order: &[usize],
+    styles: &[TextStyle],
+) -> TextBounds {
+    let writing_mode = styles[0].writing_mode;
+    let mut pen = Vector2F::zero();
+    let mut ascent = 0.0f32;
+    let mut descent = 0.0f32;
+    let mut ink: Option<RectF> = None;
+    for &ix in visual_order {
+        let fragment = &fragments[ix];
+        let size = styles[fragment.style].size;
+        let (fragment_ascent, fragment_descent) = fragment_extents(fragment, size);
+        ascent = ascent.max(fragment_ascent);
+        descent = descent.min(fragment_descent);
+        // The ink of an inline object is its whole box.
+        let ink_rect = match fragment.placeholder {
+            Some(placeholder) => Some(logical_rect(
+                writing_mode,
+                Vector2F::zero(),
+                fragment.advance,
+                placeholder.ascent,
+                placeholder.descent,
+            )),
+            None => fragment_ink_bounds(fragment, size),
+        };
+        if let Some(rect) = ink_rect {
+            let rect = rect + pen;
+            ink = Some(ink.map_or(rect, |ink| ink.union_rect(rect)));
+        }
+        pen += fragment.advance;
+    }
+    TextBounds {
+        logical: logical_rect(writing_mode, Vector2F::zero(), pen, ascent, descent),
+        ink: ink.unwrap_or_default(),
+    }
+}
+
+/// The ascent and descent of the fragment's font, scaled to the text size,
+/// or of its inline object. The descent is negative.
+pub(crate) fn fragment_extents(fragment: &LayoutFragment, size: f32) -> (f32, f32) {
+    if let Some(placeholder) = fragment.placeholder {
+        return (placeholder.ascent, placeholder.descent);
+    }
+    let metrics = font_metrics(&fragment.font, size);
+    (metrics.ascent, metrics.descent)
+}
+
+/// The logical box of the text between two positions on the line, with the
+/// given ascent and descent. In vertical text, this is centered on the line.
+pub(crate) fn logical_rect(
+    writing_mode: WritingMode,
+    from: Vector2F,
+    to: Vector2F,
+    ascent: f32,
+    descent: f32,
+) -> RectF {
+    match writing_mode {
+        WritingMode::Horizontal => RectF::from_points(
+            vec2f(from.x().min(to.x()), descent),
+            vec2f(from.x().max(to.x()), ascent),
+        ),
+        WritingMode::Vertical => {
+            let half_width = 0.5 * (ascent - descent);
+            RectF::from_points(
+                vec2f(-half_width, from.y().min(to.y())),
+                vec2f(half_width, from.y().max(to.y())),
+            )
+        }
+    }
+}
@@ -0,0 +1,413 @@
+//! A layout cache that can be shared between sessions and threads.
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use harfbuzz::sys::hb_script_t;
+use pathfinder_geometry::vector::Vector2F;
+
+use crate::collection::FontId;
+use crate::hb_layout::{font_instance, layout_fragment};
+use crate::session::{FragmentGlyph, LayoutFragment, Segment};
+use crate::{FontRef, FontVariation, Locale, RunOrientation, Synthesis, TextStyle};
+
+/// A cache of shaped text, keyed by text, font, script, locale and style.
+///
+/// This plays the role of the "Engine" in the requirements doc. The cache is
+/// cheap to clone; clones share the same storage, and can be used from
+/// multiple threads. The least recently used entries are evicted when the
+/// total size goes over the limit.
+#[derive(Clone)]
+pub struct LayoutCache {
+    inner: Arc<Mutex<CacheInner>>,
+}
+
+/// Statistics about the use of a `LayoutCache`.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// The number of entries currently in the cache.
+    pub entries: usize,
+    /// The estimated size of the cache contents, in bytes.
+    pub size: usize,
+}
+
+struct CacheInner {
+    max_size: usize,
+    entries: HashMap<CacheKey, CacheEntry>,
+    // Keys by the time they were last used, for LRU eviction.
+    lru: BTreeMap<u64, CacheKey>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+struct CacheEntry {
+    value: Arc<CachedFragment>,
+    last_used: u64,
+    size: usize,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    text: String,
+    font: FontId,
+    script: hb_script_t,
+    rtl: bool,
+    orientation: RunOrientation,
+    locale: Option<Locale>,
+    // Float values are keyed by their bits.
+    size: u32,
+    // Features, restricted to the text and with ranges relative to it.
+    features: Vec<([u8; 4], u32, Option<Range<usize>>)>,
+    variations: Vec<([u8; 4], u32)>,
+    auto_optical_size: bool,
+    synthesis: (u32, u32),
+    letter_spacing: u32,
+    word_spacing: u32,
+}
+
+// The part of a layout fragment that doesn't depend on its position in the
+// text or on the font object, which can't be shared between threads.
+struct CachedFragment {
+    advance: Vector2F,
+    glyphs: Vec<FragmentGlyph>,
+    variations: Vec<FontVariation>,
+    synthesis: Synthesis,
+}
+
+impl LayoutCache {
+    /// Create a cache that holds up to approximately `max_size` bytes.
+    pub fn new(max_size: usize) -> LayoutCache {
+        LayoutCache {
+            inner: Arc::new(Mutex::new(CacheInner {
+                max_size,
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                stats: CacheStats::default(),
+            })),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().unwrap().stats
+    }
+
+    /// Remove all entries. The hit and miss counts are kept.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.clear();
+        inner.lru.clear();
+        inner.stats.entries = 0;
+        inner.stats.size = 0;
+    }
+
+    /// Shape a fragment, reusing a cached result if there is one.
+    pub(crate) fn layout_fragment(
+        &self,
+        style: &TextStyle,
+        font: &FontRef,
+        segment: &Segment,
+        text: &str,
+        range: Range<usize>,
+    ) -> LayoutFragment {
+        let key = CacheKey::new(style, font, segment, text, range.clone());
+        let cached = self.inner.lock().unwrap().get(&key);
+        let value = match cached {
+            Some(value) => value,
+            None => {
+                // Shape without holding the lock, so other threads can proceed.
+                let fragment = layout_fragment(style, font, segment, text, range.clone());
+                let value = Arc::new(CachedFragment {
+                    advance: fragment.advance,
+                    glyphs: fragment.glyphs,
+                    variations: fragment.variations,
+                    synthesis: fragment.synthesis,
+                });
+                self.inner.lock().unwrap().insert(key, value.clone());
+                value
+            }
+        };
+        LayoutFragment {
+            substr_start: range.start,
+            substr_len: range.len(),
+            script: segment.script,
+            level: segment.level,
+            orientation: segment.orientation,
+            locale: segment.locale.clone(),
+            style: segment.style,
+            advance: value.advance,
+            glyphs: value.glyphs.clone(),
+            font: font.clone(),
+            variations: value.variations.clone(),
+            synthesis: value.synthesis,
+            placeholder: None,
+        }
+    }
+}
+
+impl CacheInner {
+    fn get(&mut self, key: &CacheKey) -> Option<Arc<CachedFragment>> {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(key) {
+            let key = self.lru.remove(&entry.last_used).unwrap();
+            self.lru.insert(clock, key);
+            entry.last_used = clock;
+            self.stats.hits += 1;
+            Some(entry.value.clone())
+        } else {
+            self.stats.misses += 1;
+            None
+        }
+    }
+
+    fn insert(&mut self, key: CacheKey, value: Arc<CachedFragment>) {
+        // Another thread may have shaped the same text in the meantime.
+        if self.entries.contains_key(&key) {
+            return;
+        }
+        self.clock += 1;
+        let size = key.text.len()
+            + value.glyphs.len() * mem::size_of::<FragmentGlyph>()
+            + mem::size_of::<CacheKey>()
+            + mem::size_of::<CachedFragment>();
+        self.lru.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                last_used: self.clock,
+                size,
+            },
+        );
+        self.stats.size += size;
+        while self.stats.size > self.max_size {
+            let (_, key) = match self.lru.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            let entry = self.entries.remove(&key).unwrap();
+            self.stats.size -= entry.size;
+            self.stats.evictions += 1;
+        }
+        self.stats.entries = self.entries.len();
+    }
+}
+
+impl CacheKey {
+    fn new(
+        style: &TextStyle,
+        font: &FontRef,
+        segment: &Segment,
+        text: &str,
+        range: Range<usize>,
+    ) -> CacheKey {
+        let features = style
+            .features
+            .iter()
+            .filter_map(|feature| {
+                let feature_range = match &feature.range {
+                    None => None,
+                    Some(r) => {
+                        let start = r.start.max(range.start);
+                        let end = r.end.min(range.end);
+                        if start >= end {
+                            return None;
+                        }
+                        Some(start - range.start..end - range.start)
+                    }
+                };
+                Some((feature.tag, feature.value, feature_range))
+            })
+            .collect();
+        let (_, synthesis) = font_instance(style, font);
+        CacheKey {
+            text: text[range].to_string(),
+            font: FontId::from_font(font),
+            script: segment.script,
+            rtl: segment.level & 1 != 0,
+            orientation: segment.orientation,
+            locale: segment.locale.clone(),
+            size: style.size.to_bits(),
+            features,
+            variations: style
+                .variations
+                .iter()
+                .map(|variation| (variation.tag, variation.value.to_bits()))
+                .collect(),
+            auto_optical_size: style.auto_optical_size,
+            synthesis: (synthesis.embolden.to_bits(), synthesis.skew.to_bits()),
+            letter_spacing: style.letter_spacing.to_bits(),
+            word_spacing: style.word_spacing.to_bits(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use font_kit::family_name::FamilyName;
+    use font_kit::properties::Properties;
+    use font_kit::source::SystemSource;
+    use harfbuzz::sys::HB_SCRIPT_LATIN;
+
+    use super::LayoutCache;
+    use crate::session::{LayoutFragment, Segment};
+    use crate::{FontFeature, FontRef, FontVariation, Locale, RunOrientation, TextStyle};
+
+    fn font() -> FontRef {
+        let font = SystemSource::new()
+            .select_best_match(&[FamilyName::SansSerif], &Properties::new())
+            .unwrap()
+            .load()
+            .unwrap();
+        FontRef::new(font)
+    }
+
+    fn segment(text: &str, locale: Option<Locale>) -> Segment {
+        Segment {
+            range: 0..text.len(),
+            level: 0,
+            script: HB_SCRIPT_LATIN,
+            orientation: RunOrientation::Horizontal,
+            locale,
+            style: 0,
+            placeholder: None,
+        }
+    }
+
+    fn shape(cache: &LayoutCache, font: &FontRef, style: &TextStyle, text: &str) -> LayoutFragment {
+        cache.layout_fragment(style, font, &segment(text, None), text, 0..text.len())
+    }
+
+    // The size of the entry for a single letter.
+    fn entry_size(font: &FontRef) -> usize {
+        let cache = LayoutCache::new(usize::MAX);
+        shape(&cache, font, &TextStyle::new(16.0), "a");
+        cache.stats().size
+    }
+
+    #[test]
+    fn hits_and_misses_are_counted() {
+        let font = font();
+        let style = TextStyle::new(16.0);
+        let cache = LayoutCache::new(1 << 20);
+        let first = shape(&cache, &font, &style, "abc");
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (0, 1, 1));
+        assert!(stats.size > 0);
+        let second = shape(&cache, &font, &style, "abc");
+        assert_eq!((cache.stats().hits, cache.stats().misses), (1, 1));
+        let glyph_ids = |fragment: &LayoutFragment| -> Vec<u32> {
+            fragment.glyphs.iter().map(|glyph| glyph.glyph_id).collect()
+        };
+        assert_eq!(glyph_ids(&first), glyph_ids(&second));
+        assert_eq!(first.advance, second.advance);
+        shape(&cache, &font, &style, "abd");
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 2));
+        // Clearing keeps the counts.
+        cache.clear();
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.hits, stats.misses, stats.entries, stats.size),
+            (1, 2, 0, 0)
+        );
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let font = font();
+        let style = TextStyle::new(16.0);
+        let cache = LayoutCache::new(2 * entry_size(&font));
+        shape(&cache, &font, &style, "a");
+        shape(&cache, &font, &style, "b");
+        // Using "a" makes "b" the least recently used.
+        shape(&cache, &font, &style, "a");
+        shape(&cache, &font, &style, "c");
+        let stats = cache.stats();
+        assert_eq!((stats.evictions, stats.entries), (1, 2));
+        shape(&cache, &font, &style, "a");
+        assert_eq!(cache.stats().hits, 2);
+        shape(&cache, &font, &style, "b");
+        assert_eq!(cache.stats().hits, 2);
+        assert_eq!(cache.stats().misses, 4);
+    }
+
+    #[test]
+    fn size_is_limited() {
+        let font = font();
+        let style = TextStyle::new(16.0);
+        let max_size = 3 * entry_size(&font);
+        let cache = LayoutCache::new(max_size);
+        for c in 'a'..='t' {
+            shape(&cache, &font, &style, &c.to_string());
+            assert!(cache.stats().size <= max_size);
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evictions), (3, 17));
+    }
+
+    #[test]
+    fn each_key_field_is_compared() {
+        let font = font();
+        let base = TextStyle::new(16.0);
+        let styles = [
+            TextStyle {
+                features: vec![FontFeature::new(b"liga", 0)],
+                ..base.clone()
+            },
+            TextStyle {
+                variations: vec![FontVariation::new(b"wght", 700.0)],
+                ..base.clone()
+            },
+            TextStyle {
+                letter_spacing: 0.1,
+                ..base.clone()
+            },
+            TextStyle {
+                word_spacing: 0.1,
+                ..base.clone()
+            },
+            TextStyle::new(17.0),
+        ];
+        let text = "fi fi";
+        for (i, style) in styles.iter().enumerate() {
+            let cache = LayoutCache::new(1 << 20);
+            shape(&cache, &font, &base, text);
+            shape(&cache, &font, style, text);
+            let stats = cache.stats();
+            assert_eq!((stats.hits, stats.misses), (0, 2), "style {}", i);
+        }
+        let cache = LayoutCache::new(1 << 20);
+        shape(&cache, &font, &base, text);
+        let locale = Some(Locale::new("tr"));
+        cache.layout_fragment(&base, &font, &segment(text, locale), text, 0..text.len());
+        assert_eq!((cache.stats().hits, cache.stats().misses), (0, 2));
+    }
+
+    #[test]
+    fn features_outside_the_text_are_ignored() {
+        let font = font();
+        let cache = LayoutCache::new(1 << 20);
+        let text = "fi fi";
+        let style = TextStyle {
+            features: vec![FontFeature::with_range(b"liga", 0, 0..2)],
+            ..TextStyle::new(16.0)
+        };
+        let segment = segment(text, None);
+        cache.layout_fragment(&TextStyle::new(16.0), &font, &segment, text, 3..5);
+        cache.layout_fragment(&style, &font, &segment, text, 3..5);
+        assert_eq!(cache.stats().hits, 1);
+        // The first word has the same text as the second, but the feature
+        // applies to it.
+        cache.layout_fragment(&TextStyle::new(16.0), &font, &segment, text, 0..2);
+        assert_eq!(cache.stats().hits, 2);
+        cache.layout_fragment(&style, &font, &segment, text, 0..2);
+        assert_eq!((cache.stats().hits, cache.stats().misses), (2, 2));
+    }
+}
@@ -2,9 +2,17 @@
 
 use std::fmt;
 use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
-use crate::Font;
+use font_kit::properties::Properties;
+use harfbuzz::sys::{
+    HB_SCRIPT_BOPOMOFO, HB_SCRIPT_HAN, HB_SCRIPT_HANGUL, HB_SCRIPT_HIRAGANA, HB_SCRIPT_KATAKANA,
+};
+
+use crate::matching::find_best_match;
+use crate::unicode_funcs::lookup_script;
+use crate::{Font, Locale, TextStyle};
 
 /// A collection of fonts
 #[derive(Debug)]
@@ -14,14 +22,20 @@ pub struct FontCollection {
 
 #[derive(Debug)]
 pub struct FontFamily {
-    // TODO: multiple weights etc
     pub(crate) fonts: Vec<FontRef>,
+    // The properties of each font, for matching.
+    pub(crate) properties: Vec<Properties>,
+    // The languages the family is designed for, used to resolve Han
+    // unification. This is metadata from the system font configuration, not
+    // something found in the font itself.
+    pub(crate) languages: Vec<Locale>,
 }
 
 // Design question: deref to Font?
 #[derive(Clone)]
 pub struct FontRef {
     pub font: Arc<Font>,
+    pub(crate) id: FontId,
 }
 
 impl fmt::Debug for FontRef {
@@ -33,26 +47,63 @@ impl fmt::Debug for FontRef {
 pub struct Itemizer<'a> {
     text: &'a str,
     collection: &'a FontCollection,
+    // The first CJK locale, if any.
+    locale: Option<&'a Locale>,
+    // The index of the best matching font in each family.
+    font_ixs: Vec<usize>,
     ix: usize,
 }
 
 impl FontRef {
+    // On platforms where `Font` is not `Send` or `Sync`, such as with the
+    // FreeType loader, neither is `FontRef`. The `Arc` still makes clones
+    // cheap, and lets fonts be shared between threads where the loader allows.
+    #[allow(clippy::arc_with_non_send_sync)]
     pub fn new(font: Font) -> FontRef {
+        let id = FontId::new(&font);
         FontRef {
             font: Arc::new(font),
+            id,
         }
     }
 }
 
+impl Default for FontFamily {
+    fn default() -> FontFamily {
+        FontFamily::new()
+    }
+}
+
 impl FontFamily {
     pub fn new() -> FontFamily {
-        FontFamily { fonts: Vec::new() }
+        FontFamily {
+            fonts: Vec::new(),
+            properties: Vec::new(),
+            languages: Vec::new(),
+        }
     }
 
     pub fn add_font(&mut self, font: FontRef) {
+        self.properties.push(font.font.properties());
         self.fonts.push(font);
     }
 
+    /// The font in the family closest to the requested weight, stretch and
+    /// style, according to the CSS font matching algorithm.
+    pub fn best_match(&self, properties: &Properties) -> Option<&FontRef> {
+        find_best_match(&self.properties, properties).map(|ix| &self.fonts[ix])
+    }
+
+    /// Declare a language the family is designed for, for example `ja` for a
+    /// font with Japanese glyph forms.
+    pub fn add_language(&mut self, locale: Locale) {
+        self.languages.push(locale);
+    }
+
+    pub fn languages(&self) -> &[Locale] {
+        &self.languages
+    }
+
     /// Create a collection consisting of a single font
     pub fn new_from_font(font: Font) -> FontFamily {
         let mut result = FontFamily::new();
@@ -61,7 +112,11 @@ impl FontFamily {
     }
 
     pub fn supports_codepoint(&self, c: char) -> bool {
-        if let Some(font) = self.fonts.first() {
+        self.font_supports_codepoint(0, c)
+    }
+
+    fn font_supports_codepoint(&self, font_ix: usize, c: char) -> bool {
+        if let Some(font) = self.fonts.get(font_ix) {
             let glyph_id = font.font.glyph_for_char(c);
             // TODO(font-kit): We're getting Some(0) for unsupported glyphs on CoreText
             // and DirectWrite
@@ -72,6 +127,12 @@ impl FontFamily {
     }
 }
 
+impl Default for FontCollection {
+    fn default() -> FontCollection {
+        FontCollection::new()
+    }
+}
+
 impl FontCollection {
     pub fn new() -> FontCollection {
         FontCollection {
@@ -84,31 +145,121 @@ impl FontCollection {
     }
 
     pub fn itemize<'a>(&'a self, text: &'a str) -> Itemizer<'a> {
+        self.itemize_impl(text, None, &Properties::new())
+    }
+
+    /// Itemize the text for the given style.
+    ///
+    /// Within each family, the font closest to the requested properties is
+    /// chosen. For characters affected by Han unification, families whose
+    /// language matches the first CJK locale of the style are preferred.
+    pub fn itemize_with_style<'a>(&'a self, text: &'a str, style: &'a TextStyle) -> Itemizer<'a> {
+        self.itemize_impl(text, style.locales.first_cjk(), &style.properties)
+    }
+
+    fn itemize_impl<'a>(
+        &'a self,
+        text: &'a str,
+        locale: Option<&'a Locale>,
+        properties: &Properties,
+    ) -> Itemizer<'a> {
+        let font_ixs = self
+            .families
+            .iter()
+            .map(|family| find_best_match(&family.properties, properties).unwrap_or(0))
+            .collect();
         Itemizer {
             text,
             collection: self,
+            locale,
+            font_ixs,
             ix: 0,
         }
     }
 
-    // TODO: other style params, including locale list
-    fn choose_font(&self, c: char) -> usize {
-        self.families
-            .iter()
-            .position(|family| family.supports_codepoint(c))
-            .unwrap_or(0)
+    // Choose the family for the character, given the font chosen within each
+    // family.
+    fn choose_font(&self, c: char, locale: Option<&Locale>, font_ixs: &[usize]) -> usize {
+        choose_family(&self.families, c, locale, |ix| {
+            self.families[ix].font_supports_codepoint(font_ixs[ix], c)
+        })
+    }
+}
+
+// Choose the family for the character, given which families support it.
+fn choose_family(
+    families: &[FontFamily],
+    c: char,
+    locale: Option<&Locale>,
+    supports: impl Fn(usize) -> bool,
+) -> usize {
+    if let Some(locale) = locale {
+        if is_han_unified(c) {
+            let localized = (0..families.len()).find(|&ix| {
+                families[ix]
+                    .languages
+                    .iter()
+                    .any(|lang| lang.matches_han_variant(locale))
+                    && supports(ix)
+            });
+            if let Some(ix) = localized {
+                return ix;
+            }
+        }
     }
+    (0..families.len()).find(|&ix| supports(ix)).unwrap_or(0)
+}
+
+// Whether the preferred glyph forms for the character depend on which CJK
+// locale is in effect.
+fn is_han_unified(c: char) -> bool {
+    let script = lookup_script(c.into());
+    script == HB_SCRIPT_HAN
+        || script == HB_SCRIPT_HIRAGANA
+        || script == HB_SCRIPT_KATAKANA
+        || script == HB_SCRIPT_BOPOMOFO
+        || script == HB_SCRIPT_HANGUL
+        // CJK symbols and punctuation, and fullwidth forms
+        || ('\u{3000}'..='\u{303f}').contains(&c)
+        || ('\u{ff00}'..='\u{ffef}').contains(&c)
 }
 
-// This is the PostScript name of the font. Eventually this should be a unique ID.
+// Identifies a font in caches. Separately loaded copies of the same font,
+// such as on different threads, get equal ids and share cache entries.
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
-pub(crate) struct FontId {
-    postscript_name: String,
+pub(crate) struct FontId(Arc<FontIdKey>);
+
+#[derive(PartialEq, Eq, Hash, Debug)]
+enum FontIdKey {
+    // The `head` table includes a checksum of the whole font file, which
+    // tells apart different fonts with the same name.
+    Named {
+        postscript_name: String,
+        head: Box<[u8]>,
+    },
+    // Fonts without a name or a `head` table are never considered the same
+    // as another font.
+    Unique(u64),
 }
 
+static NEXT_UNIQUE_FONT_ID: AtomicU64 = AtomicU64::new(0);
+
 impl FontId {
+    fn new(font: &Font) -> FontId {
+        let postscript_name = font.postscript_name();
+        let head = font.load_font_table(u32::from_be_bytes(*b"head"));
+        let key = match (postscript_name, head) {
+            (Some(postscript_name), Some(head)) => FontIdKey::Named {
+                postscript_name,
+                head,
+            },
+            _ => FontIdKey::Unique(NEXT_UNIQUE_FONT_ID.fetch_add(1, Ordering::Relaxed)),
+        };
+        FontId(Arc::new(key))
+    }
+
     pub(crate) fn from_font(font: &FontRef) -> FontId {
-        FontId { postscript_name: font.font.postscript_name().unwrap_or_default() }
+        font.id.clone()
     }
 }
 
@@ -120,18 +271,91 @@ impl<'a> Iterator for Itemizer<'a> {
         let mut chars_iter = self.text[start..].chars();
         if let Some(c) = chars_iter.next() {
             let mut end = start + c.len_utf8();
-            let font_ix = self.collection.choose_font(c);
+            let font_ix = self.collection.choose_font(c, self.locale, &self.font_ixs);
             debug!("{}: {}", c, font_ix);
-            while let Some(c) = chars_iter.next() {
-                if font_ix != self.collection.choose_font(c) {
+            for c in chars_iter {
+                if font_ix != self.collection.choose_font(c, self.locale, &self.font_ixs) {
                     break;
                 }
                 end += c.len_utf8();
             }
             self.ix = end;
-            Some((start..end, &self.collection.families[font_ix].fonts[0]))
+            let family = &self.collection.families[font_ix];
+            Some((start..end, &family.fonts[self.font_ixs[font_ix]]))
         } else {
             None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use font_kit::family_name::FamilyName;
+    use font_kit::properties::{Properties, Weight};
+    use font_kit::source::SystemSource;
+
+    use super::{choose_family, FontFamily, FontId, FontRef};
+    use crate::Locale;
+
+    fn load_font(properties: &Properties) -> FontRef {
+        let font = SystemSource::new()
+            .select_best_match(&[FamilyName::SansSerif], properties)
+            .unwrap()
+            .load()
+            .unwrap();
+        FontRef::new(font)
+    }
+
+    #[test]
+    fn font_ids_match_for_the_same_font() {
+        let regular = load_font(&Properties::new());
+        assert_eq!(
+            FontId::from_font(&regular),
+            FontId::from_font(&load_font(&Properties::new()))
+        );
+        assert_eq!(
+            FontId::from_font(&regular),
+            FontId::from_font(&regular.clone())
+        );
+        let bold = load_font(Properties::new().weight(Weight::BOLD));
+        assert_ne!(FontId::from_font(&regular), FontId::from_font(&bold));
+    }
+
+    fn family(language: &str) -> FontFamily {
+        let mut family = FontFamily::new();
+        family.add_language(Locale::new(language));
+        family
+    }
+
+    #[test]
+    fn locale_chooses_among_han_families() {
+        let families = [family("zh-Hans"), family("ja")];
+        let choose =
+            |c, locale: &str| choose_family(&families, c, Some(&Locale::new(locale)), |_| true);
+        assert_eq!(choose('直', "ja"), 1);
+        assert_eq!(choose('直', "ja-JP"), 1);
+        assert_eq!(choose('直', "zh-Hans"), 0);
+        assert_eq!(choose('直', "zh-CN"), 0);
+        // CJK punctuation also differs between locales.
+        assert_eq!(choose('\u{3002}', "ja"), 1);
+        // Without a locale, or for other characters, the order decides.
+        assert_eq!(choose_family(&families, '直', None, |_| true), 0);
+        assert_eq!(choose('a', "ja"), 0);
+    }
+
+    #[test]
+    fn localized_family_must_support_the_character() {
+        let families = [family("zh-Hans"), family("ja"), family("en")];
+        let locale = Locale::new("ja");
+        assert_eq!(
+            choose_family(&families, '直', Some(&locale), |ix| ix != 1),
+            0
+        );
+        assert_eq!(
+            choose_family(&families, '直', Some(&locale), |ix| ix == 2),
+            2
+        );
+        // Characters that no family supports go to the first family.
+        assert_eq!(choose_family(&families, '直', Some(&locale), |_| false), 0);
+    }
+}
@@ -0,0 +1,328 @@
+//! Decoration lines, such as underlines, drawn across several runs.
+
+use std::ops::Range;
+
+use font_kit::hinting::HintingOptions;
+use font_kit::outline::OutlineSink;
+use pathfinder_geometry::line_segment::LineSegment2F;
+use pathfinder_geometry::vector::{vec2f, Vector2F};
+
+use crate::metrics::DecorationMetrics;
+use crate::session::LayoutRun;
+use crate::RunOrientation;
+
+/// A decoration line with one position and thickness across a sequence of
+/// runs, such as a line of text, even when the runs use different fonts.
+///
+/// Coordinates are the same as for glyph offsets. Decoration lines are for
+/// horizontal text; runs that are not horizontal don't contribute skip ink
+/// intervals.
+#[derive(Clone, PartialEq, Debug)]
+pub struct DecorationLine {
+    /// The y coordinate of the top of the line, relative to the baseline.
+    pub position: f32,
+    pub thickness: f32,
+    /// The x coordinates covered by the runs.
+    pub extent: Range<f32>,
+    /// The x coordinates where the line would cross glyph outlines, in
+    /// increasing order. Each is padded by the thickness of the line on both
+    /// sides. Renderers that skip ink leave gaps in the line there.
+    pub skip_ink: Vec<Range<f32>>,
+}
+
+impl DecorationLine {
+    /// An underline for the runs. It takes the lowest position and the
+    /// largest thickness of the underlines of the runs' fonts, so that it
+    /// clears the descenders of all of them. Inline objects are ignored.
+    pub fn underline(runs: &[LayoutRun]) -> DecorationLine {
+        let metrics = text_runs(runs)
+            .map(|run| run.underline())
+            .fold(None, |merged: Option<DecorationMetrics>, metrics| {
+                Some(merged.map_or(metrics, |merged| DecorationMetrics {
+                    position: merged.position.min(metrics.position),
+                    thickness: merged.thickness.max(metrics.thickness),
+                }))
+            })
+            .unwrap_or_default();
+        let band = metrics.position - metrics.thickness..metrics.position;
+        let mut skip_ink = Vec::new();
+        for run in text_runs(runs) {
+            add_intercepts(run, band.clone(), metrics.thickness, &mut skip_ink);
+        }
+        DecorationLine {
+            position: metrics.position,
+            thickness: metrics.thickness,
+            extent: runs_extent(runs),
+            skip_ink: merge_intervals(skip_ink),
+        }
+    }
+
+    /// A strikeout for the runs. It takes the largest thickness of the
+    /// strikeouts of the runs' fonts, centered at the average of their
+    /// centers, weighted by the advance of each run. Inline objects are
+    /// ignored. There are no skip ink intervals.
+    pub fn strikeout(runs: &[LayoutRun]) -> DecorationLine {
+        let mut thickness = 0.0f32;
+        let mut center_sum = 0.0;
+        let mut weight_sum = 0.0;
+        for run in text_runs(runs) {
+            let metrics = run.strikeout();
+            // Runs without an advance still count, for a line of them.
+            let weight = run.advance().length().max(f32::EPSILON);
+            thickness = thickness.max(metrics.thickness);
+            center_sum += weight * (metrics.position - 0.5 * metrics.thickness);
+            weight_sum += weight;
+        }
+        let center = if weight_sum > 0.0 {
+            center_sum / weight_sum
+        } else {
+            0.0
+        };
+        DecorationLine {
+            position: center + 0.5 * thickness,
+            thickness,
+            extent: runs_extent(runs),
+            skip_ink: Vec::new(),
+        }
+    }
+}
+
+// The runs that have glyphs, rather than inline objects.
+fn text_runs<'a, 'b>(runs: &'b [LayoutRun<'a>]) -> impl Iterator<Item = &'b LayoutRun<'a>> {
+    runs.iter().filter(|run| run.placeholder().is_none())
+}
+
+fn runs_extent(runs: &[LayoutRun]) -> Range<f32> {
+    let ends = runs.iter().flat_map(|run| {
+        let start = run.offset().x();
+        [start, start + run.advance().x()]
+    });
+    let (min, max) = ends.fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), x| {
+        (min.min(x), max.max(x))
+    });
+    if min <= max {
+        min..max
+    } else {
+        0.0..0.0
+    }
+}
+
+// Add the x ranges where the outlines of the run's glyphs enter the band of
+// y coordinates, padded on both sides.
+//
+// The outlines are those of the default instance of a variable font: the
+// loader doesn't apply the run's variations to them, so the intervals can be
+// off for other instances.
+fn add_intercepts(
+    run: &LayoutRun,
+    band: Range<f32>,
+    padding: f32,
+    intervals: &mut Vec<Range<f32>>,
+) {
+    if run.orientation() != RunOrientation::Horizontal {
+        return;
+    }
+    let font = &run.font().font;
+    let scale = run.size() / (font.metrics().units_per_em as f32);
+    let synthesis = run.synthesis();
+    // Emboldening grows outlines by half its amount in every direction.
+    let grow = 0.5 * synthesis.embolden;
+    let band = band.start - grow..band.end + grow;
+    for glyph in run.glyphs() {
+        let mut sink = BandIntercepts {
+            band: band.clone(),
+            origin: glyph.offset,
+            scale,
+            skew: synthesis.skew.to_radians().tan(),
+            pen: Vector2F::zero(),
+            contour_start: Vector2F::zero(),
+            range: None,
+        };
+        if font
+            .outline(glyph.glyph_id, HintingOptions::None, &mut sink)
+            .is_err()
+        {
+            continue;
+        }
+        if let Some(range) = sink.range {
+            let padding = padding + grow;
+            intervals.push(range.start - padding..range.end + padding);
+        }
+    }
+}
+
+// Sort intervals and merge the ones that overlap.
+fn merge_intervals(mut intervals: Vec<Range<f32>>) -> Vec<Range<f32>> {
+    intervals.sort_by(|a, b| a.start.total_cmp(&b.start));
+    let mut merged: Vec<Range<f32>> = Vec::with_capacity(intervals.len());
+    for interval in intervals {
+        match merged.last_mut() {
+            Some(last) if interval.start <= last.end => last.end = last.end.max(interval.end),
+            _ => merged.push(interval),
+        }
+    }
+    merged
+}
+
+// Curves are flattened into this many line segments.
+const CURVE_STEPS: usize = 8;
+
+// An outline sink that finds the x range of the parts of a glyph outline
+// inside a band of y coordinates. Points are placed like the glyph, with the
+// font's scale and any faux italic skew applied.
+struct BandIntercepts {
+    band: Range<f32>,
+    origin: Vector2F,
+    scale: f32,
+    skew: f32,
+    pen: Vector2F,
+    contour_start: Vector2F,
+    range: Option<Range<f32>>,
+}
+
+impl BandIntercepts {
+    fn transform(&self, point: Vector2F) -> Vector2F {
+        let point = point * self.scale;
+        self.origin + vec2f(point.x() + point.y() * self.skew, point.y())
+    }
+
+    // Add the part of the segment from the pen to `to` inside the band.
+    fn segment_to(&mut self, to: Vector2F) {
+        let from = self.pen;
+        self.pen = to;
+        let dy = to.y() - from.y();
+        let (t_min, t_max) = if dy == 0.0 {
+            if from.y() < self.band.start || from.y() > self.band.end {
+                return;
+            }
+            (0.0, 1.0)
+        } else {
+            let a = (self.band.start - from.y()) / dy;
+            let b = (self.band.end - from.y()) / dy;
+            (a.min(b).max(0.0), a.max(b).min(1.0))
+        };
+        if t_min > t_max {
+            return;
+        }
+        let x0 = from.x() + (to.x() - from.x()) * t_min;
+        let x1 = from.x() + (to.x() - from.x()) * t_max;
+        let (lo, hi) = (x0.min(x1), x0.max(x1));
+        self.range = Some(match self.range.take() {
+            Some(range) => range.start.min(lo)..range.end.max(hi),
+            None => lo..hi,
+        });
+    }
+}
+
+impl OutlineSink for BandIntercepts {
+    fn move_to(&mut self, to: Vector2F) {
+        self.pen = self.transform(to);
+        self.contour_start = self.pen;
+    }
+
+    fn line_to(&mut self, to: Vector2F) {
+        let to = self.transform(to);
+        self.segment_to(to);
+    }
+
+    fn quadratic_curve_to(&mut self, ctrl: Vector2F, to: Vector2F) {
+        let (from, ctrl, to) = (self.pen, self.transform(ctrl), self.transform(to));
+        for i in 1..=CURVE_STEPS {
+            let t = i as f32 / CURVE_STEPS as f32;
+            let u = 1.0 - t;
+            self.segment_to(from * (u * u) + ctrl * (2.0 * u * t) + to * (t * t));
+        }
+    }
+
+    fn cubic_curve_to(&mut self, ctrl: LineSegment2F, to: Vector2F) {
+        let from = self.pen;
+        let (ctrl0, ctrl1) = (self.transform(ctrl.from()), self.transform(ctrl.to()));
+        let to = self.transform(to);
+        for i in 1..=CURVE_STEPS {
+            let t = i as f32 / CURVE_STEPS as f32;
+            let u = 1.0 - t;
+            self.segment_to(
+                from * (u * u * u)
+                    + ctrl0 * (3.0 * u * u * t)
+                    + ctrl1 * (3.0 * u * t * t)
+                    + to * (t * t * t),
+            );
+        }
+    }
+
+    fn close(&mut self) {
+        let start = self.contour_start;
+        self.segment_to(start);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use font_kit::family_name::FamilyName;
+    use font_kit::properties::Properties;
+    use font_kit::source::SystemSource;
+
+    use super::{merge_intervals, DecorationLine};
+    use crate::{FontCollection, FontFamily, LayoutSession, TextStyle};
+
+    fn underline(text: &str) -> DecorationLine {
+        let font = SystemSource::new()
+            .select_best_match(&[FamilyName::SansSerif], &Properties::new())
+            .unwrap()
+            .load()
+            .unwrap();
+        let mut collection = FontCollection::new();
+        collection.add_family(FontFamily::new_from_font(font));
+        let session = LayoutSession::create(text, &TextStyle::new(16.0), &collection);
+        let runs: Vec<_> = session.iter_all().collect();
+        DecorationLine::underline(&runs)
+    }
+
+    #[test]
+    fn underline_skips_descenders() {
+        let line = underline("gyp");
+        assert!(line.position < 0.0);
+        assert_eq!(line.extent.start, 0.0);
+        // Each descender crosses the line, within the extent of the text.
+        assert!(!line.skip_ink.is_empty());
+        for gap in &line.skip_ink {
+            assert!(gap.start < gap.end);
+            assert!(gap.end > line.extent.start && gap.start < line.extent.end);
+        }
+        // Letters without descenders leave the line whole.
+        let line = underline("ace");
+        assert!(line.extent.end > 0.0);
+        assert!(line.skip_ink.is_empty());
+    }
+
+    #[test]
+    fn merge_empty() {
+        assert_eq!(merge_intervals(Vec::new()), Vec::new());
+    }
+
+    #[test]
+    fn merge_sorts_disjoint_intervals() {
+        assert_eq!(
+            merge_intervals(vec![5.0..6.0, 1.0..2.0, 3.0..4.0]),
+            vec![1.0..2.0, 3.0..4.0, 5.0..6.0]
+        );
+    }
+
+    #[test]
+    fn merge_overlapping_intervals() {
+        assert_eq!(
+            merge_intervals(vec![3.0..5.0, 1.0..4.0, 8.0..9.0]),
+            vec![1.0..5.0, 8.0..9.0]
+        );
+        // An interval inside another doesn't shorten it.
+        assert_eq!(merge_intervals(vec![1.0..6.0, 2.0..3.0]), vec![1.0..6.0]);
+    }
+
+    #[test]
+    fn merge_touching_intervals() {
+        assert_eq!(
+            merge_intervals(vec![2.0..3.0, 1.0..2.0, -1.0..0.5]),
+            vec![-1.0..0.5, 1.0..3.0]
+        );
+    }
+}
@@ -1,48 +1,116 @@
 //! A HarfBuzz shaping back-end.
 
-use pathfinder_geometry::vector::{vec2i, Vector2F};
+use pathfinder_geometry::rect::{RectF, RectI};
+use pathfinder_geometry::vector::{vec2f, vec2i, Vector2F};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::Range;
+use std::ptr;
+use std::rc::Rc;
 
+use font_kit::metrics::Metrics;
+use font_kit::properties::Properties;
 use harfbuzz::sys::{
     hb_buffer_get_glyph_infos, hb_buffer_get_glyph_positions, hb_face_create, hb_face_destroy,
-    hb_face_reference, hb_face_t, hb_font_create, hb_font_destroy, hb_position_t, hb_shape,
+    hb_face_reference, hb_face_t, hb_font_create, hb_font_destroy, hb_font_get_glyph_extents,
+    hb_font_set_variations, hb_font_t, hb_glyph_extents_t, hb_position_t, hb_shape,
 };
 use harfbuzz::sys::{
-    hb_glyph_info_get_glyph_flags, hb_script_t, HB_GLYPH_FLAG_UNSAFE_TO_BREAK, HB_SCRIPT_DEVANAGARI,
+    hb_feature_t, hb_glyph_info_get_glyph_flags, hb_script_t, hb_variation_t,
+    HB_GLYPH_FLAG_UNSAFE_TO_BREAK,
+};
+use harfbuzz::sys::{
+    HB_SCRIPT_ARABIC, HB_SCRIPT_BENGALI, HB_SCRIPT_DEVANAGARI, HB_SCRIPT_GURMUKHI,
+    HB_SCRIPT_MANDAIC, HB_SCRIPT_MODI, HB_SCRIPT_MONGOLIAN, HB_SCRIPT_NKO, HB_SCRIPT_OGHAM,
+    HB_SCRIPT_PHAGS_PA, HB_SCRIPT_PSALTER_PAHLAVI, HB_SCRIPT_SHARADA, HB_SCRIPT_SYLOTI_NAGRI,
+    HB_SCRIPT_TIRHUTA,
 };
 use harfbuzz::{Blob, Buffer, Direction, Language};
 
 use crate::collection::FontId;
-use crate::session::{FragmentGlyph, LayoutFragment};
+use crate::ot::{variation_axes, VariationAxis};
+use crate::session::{FragmentGlyph, LayoutFragment, Segment};
 use crate::unicode_funcs::install_unicode_funcs;
-use crate::{FontRef, Glyph, Layout, TextStyle};
+use crate::{
+    FontFeature, FontRef, FontVariation, Glyph, Layout, RunOrientation, Synthesis, TextStyle,
+};
 
 thread_local! {
     static HB_THREAD_DATA: RefCell<HbThreadData> = RefCell::new(HbThreadData::new());
 }
 
+// A font and the instance of it, for variable fonts.
+type FontInstanceKey = (FontId, Vec<([u8; 4], u32)>);
+
+// The most fonts, or instances of variable fonts, to keep per-thread data
+// for. Fonts can be loaded and dropped over the life of a thread, so the
+// caches are cleared when they grow beyond this.
+pub(crate) const MAX_CACHED_FONTS: usize = 64;
+
 // Per-thread data for HarfBuzz.
 struct HbThreadData {
-    hb_face_cache: HashMap<FontId, HbFace>,
+    font_data_cache: HashMap<FontId, Rc<FontData>>,
+    // Ink extents of glyphs in font units, by glyph id. None means no ink.
+    glyph_extents_cache: HashMap<FontInstanceKey, HashMap<u32, Option<RectI>>>,
 }
 
 impl HbThreadData {
     fn new() -> HbThreadData {
         HbThreadData {
-            hb_face_cache: HashMap::new(),
+            font_data_cache: HashMap::new(),
+            glyph_extents_cache: HashMap::new(),
         }
     }
 
-    fn create_hb_face_for_font(&mut self, font: &FontRef) -> HbFace {
-        (*self
-            .hb_face_cache
-            .entry(FontId::from_font(font))
-            .or_insert_with(|| HbFace::new(font)))
-        .clone()
+    fn font_data(&mut self, font: &FontRef) -> Rc<FontData> {
+        let font_id = FontId::from_font(font);
+        limit_cache_size(&mut self.font_data_cache, &font_id);
+        self.font_data_cache
+            .entry(font_id)
+            .or_insert_with(|| Rc::new(FontData::new(font)))
+            .clone()
+    }
+
+    fn glyph_extents_for_font(&mut self, key: FontInstanceKey) -> &mut HashMap<u32, Option<RectI>> {
+        limit_cache_size(&mut self.glyph_extents_cache, &key);
+        self.glyph_extents_cache.entry(key).or_default()
     }
 }
 
+// Make room for a new key by clearing the cache if it is full.
+pub(crate) fn limit_cache_size<K: Eq + Hash, V>(cache: &mut HashMap<K, V>, key: &K) {
+    if cache.len() >= MAX_CACHED_FONTS && !cache.contains_key(key) {
+        cache.clear();
+    }
+}
+
+// The data for a font that is kept per thread: the HarfBuzz face, which
+// holds a copy of the font file, and values that font-kit would otherwise
+// read from the font's tables on every call.
+struct FontData {
+    hb_face: HbFace,
+    axes: Vec<VariationAxis>,
+    properties: Properties,
+    metrics: Metrics,
+}
+
+impl FontData {
+    fn new(font: &FontRef) -> FontData {
+        FontData {
+            hb_face: HbFace::new(font),
+            axes: variation_axes(font),
+            properties: font.font.properties(),
+            metrics: font.font.metrics(),
+        }
+    }
+}
+
+// The per-thread data for the font.
+fn font_data(font: &FontRef) -> Rc<FontData> {
+    HB_THREAD_DATA.with(|hb_thread_data| hb_thread_data.borrow_mut().font_data(font))
+}
+
 pub(crate) struct HbFace {
     hb_face: *mut hb_face_t,
 }
@@ -86,22 +154,32 @@ pub fn layout_run(style: &TextStyle, font: &FontRef, text: &str) -> Layout {
         b.set_direction(Direction::LTR);
         // TODO: set this based on detected script
         b.set_script(HB_SCRIPT_DEVANAGARI);
-        b.set_language(Language::from_string("en_US"));
-        let hb_face = hb_thread_data.create_hb_face_for_font(font);
+        if let Some(locale) = style.locales.locales().first() {
+            b.set_language(Language::from_string(locale.as_str()));
+        }
+        let font_data = hb_thread_data.font_data(font);
+        let features = hb_features(&style.features, 0..text.len());
+        let variations = resolve_axes(style, &font_data.axes);
         unsafe {
-            let hb_font = hb_font_create(hb_face.hb_face);
-            hb_shape(hb_font, b.as_ptr(), std::ptr::null(), 0);
+            let hb_font = hb_font_create(font_data.hb_face.hb_face);
+            set_variations(hb_font, &variations);
+            hb_shape(
+                hb_font,
+                b.as_ptr(),
+                features.as_ptr(),
+                features.len() as u32,
+            );
             hb_font_destroy(hb_font);
             let mut n_glyph = 0;
             let glyph_infos = hb_buffer_get_glyph_infos(b.as_ptr(), &mut n_glyph);
             debug!("number of glyphs: {}", n_glyph);
-            let glyph_infos = std::slice::from_raw_parts(glyph_infos, n_glyph as usize);
+            let glyph_infos = buffer_slice(glyph_infos, n_glyph);
             let mut n_glyph_pos = 0;
             let glyph_positions = hb_buffer_get_glyph_positions(b.as_ptr(), &mut n_glyph_pos);
-            let glyph_positions = std::slice::from_raw_parts(glyph_positions, n_glyph_pos as usize);
+            let glyph_positions = buffer_slice(glyph_positions, n_glyph_pos);
             let mut total_adv = Vector2F::zero();
             let mut glyphs = Vec::new();
-            let scale = style.size / (font.font.metrics().units_per_em as f32);
+            let scale = style.size / (font_data.metrics.units_per_em as f32);
             for (glyph, pos) in glyph_infos.iter().zip(glyph_positions.iter()) {
                 let adv = vec2i(pos.x_advance, pos.y_advance);
                 let adv_f = adv.to_f32() * scale;
@@ -117,45 +195,100 @@ pub fn layout_run(style: &TextStyle, font: &FontRef, text: &str) -> Layout {
 
             Layout {
                 size: style.size,
-                glyphs: glyphs,
+                glyphs,
                 advance: total_adv,
             }
         }
     })
 }
 
+// A slice of the glyph infos or positions of a buffer. HarfBuzz returns a
+// null pointer when there are no glyphs, which a slice can't be made from.
+unsafe fn buffer_slice<'a, T>(data: *const T, len: u32) -> &'a [T] {
+    if len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, len as usize)
+    }
+}
+
+/// Shape the given range of the text, which must lie within `segment`.
 pub(crate) fn layout_fragment(
     style: &TextStyle,
     font: &FontRef,
-    script: hb_script_t,
+    segment: &Segment,
     text: &str,
+    range: Range<usize>,
 ) -> LayoutFragment {
+    let orientation = segment.orientation;
     let mut b = Buffer::new();
     install_unicode_funcs(&mut b);
-    b.add_str(text);
-    b.set_direction(Direction::LTR);
-    b.set_script(script);
-    b.set_language(Language::from_string("en_US"));
-    let hb_face = HbFace::new(font);
+    b.add_str(&text[range.clone()]);
+    if orientation == RunOrientation::Upright {
+        b.set_direction(Direction::TTB);
+    } else if segment.level & 1 == 0 {
+        b.set_direction(Direction::LTR);
+    } else {
+        b.set_direction(Direction::RTL);
+    }
+    b.set_script(segment.script);
+    if let Some(locale) = &segment.locale {
+        b.set_language(Language::from_string(locale.as_str()));
+    }
+    let font_data = font_data(font);
+    let letter_spacing = if allows_letter_spacing(segment.script) {
+        style.letter_spacing
+    } else {
+        0.0
+    };
+    let mut features = hb_features(&style.features, range.clone());
+    if letter_spacing.abs() > LIGATURE_SPACING_LIMIT {
+        // Ligatures look bad when spaced out. These come first, so explicit
+        // settings in the style still take precedence.
+        let no_ligatures = [b"liga", b"clig"].iter().map(|tag| hb_feature_t {
+            tag: u32::from_be_bytes(**tag),
+            value: 0,
+            start: 0,
+            end: u32::MAX,
+        });
+        features.splice(0..0, no_ligatures);
+    }
+    let (variations, synthesis) = instance_for_data(style, &font_data);
     unsafe {
-        let hb_font = hb_font_create(hb_face.hb_face);
-        hb_shape(hb_font, b.as_ptr(), std::ptr::null(), 0);
+        let hb_font = hb_font_create(font_data.hb_face.hb_face);
+        set_variations(hb_font, &variations);
+        hb_shape(
+            hb_font,
+            b.as_ptr(),
+            features.as_ptr(),
+            features.len() as u32,
+        );
         hb_font_destroy(hb_font);
         let mut n_glyph = 0;
         let glyph_infos = hb_buffer_get_glyph_infos(b.as_ptr(), &mut n_glyph);
         trace!("number of glyphs: {}", n_glyph);
-        let glyph_infos = std::slice::from_raw_parts(glyph_infos, n_glyph as usize);
+        let glyph_infos = buffer_slice(glyph_infos, n_glyph);
         let mut n_glyph_pos = 0;
         let glyph_positions = hb_buffer_get_glyph_positions(b.as_ptr(), &mut n_glyph_pos);
-        let glyph_positions = std::slice::from_raw_parts(glyph_positions, n_glyph_pos as usize);
+        let glyph_positions = buffer_slice(glyph_positions, n_glyph_pos);
         let mut total_adv = Vector2F::zero();
         let mut glyphs = Vec::new();
-        // TODO: we might want to store this size-invariant.
-        let scale = style.size / (font.font.metrics().units_per_em as f32);
+        let metrics = font_data.metrics;
+        let scale = style.size / (metrics.units_per_em as f32);
+        // Sideways glyphs are centered on the line, like upright ones.
+        let baseline_shift = vec2f(-0.5 * (metrics.ascent + metrics.descent) * scale, 0.0);
         for (glyph, pos) in glyph_infos.iter().zip(glyph_positions.iter()) {
             let adv = vec2i(pos.x_advance, pos.y_advance);
-            let adv_f = adv.to_f32() * scale;
-            let offset = vec2i(pos.x_offset, pos.y_offset).to_f32() * scale;
+            let mut adv_f = adv.to_f32() * scale;
+            let mut offset = vec2i(pos.x_offset, pos.y_offset).to_f32() * scale;
+            if synthesis.embolden != 0.0 && adv_f != Vector2F::zero() {
+                // Emboldening widens each glyph along the direction of its advance.
+                adv_f += adv_f * (synthesis.embolden / adv_f.length());
+            }
+            if orientation == RunOrientation::Sideways {
+                adv_f = rotate_clockwise(adv_f);
+                offset = rotate_clockwise(offset) + baseline_shift;
+            }
             let flags = hb_glyph_info_get_glyph_flags(glyph);
             let unsafe_to_break = flags & HB_GLYPH_FLAG_UNSAFE_TO_BREAK != 0;
 
@@ -169,18 +302,293 @@ pub(crate) fn layout_fragment(
             total_adv += adv_f;
             glyphs.push(g);
         }
+        let line_direction = if orientation == RunOrientation::Horizontal {
+            vec2f(1.0, 0.0)
+        } else {
+            vec2f(0.0, -1.0)
+        };
+        total_adv += apply_spacing(
+            &mut glyphs,
+            &text[range.clone()],
+            line_direction * (letter_spacing * style.size),
+            line_direction * (style.word_spacing * style.size),
+        );
 
         LayoutFragment {
             //size: style.size,
-            substr_len: text.len(),
-            script,
-            glyphs: glyphs,
+            substr_start: range.start,
+            substr_len: range.len(),
+            script: segment.script,
+            level: segment.level,
+            orientation,
+            locale: segment.locale.clone(),
+            style: segment.style,
+            glyphs,
             advance: total_adv,
             font: font.clone(),
+            variations,
+            synthesis,
+            placeholder: None,
         }
     }
 }
 
+/// The ink bounds of the fragment's glyphs, relative to the fragment origin,
+/// or None if none of them have ink.
+///
+/// The bounds account for faux bold and italic.
+pub(crate) fn fragment_ink_bounds(fragment: &LayoutFragment, size: f32) -> Option<RectF> {
+    HB_THREAD_DATA.with(|hb_thread_data| {
+        let mut hb_thread_data = hb_thread_data.borrow_mut();
+        let font_data = hb_thread_data.font_data(&fragment.font);
+        let key = (
+            FontId::from_font(&fragment.font),
+            fragment
+                .variations
+                .iter()
+                .map(|variation| (variation.tag, variation.value.to_bits()))
+                .collect(),
+        );
+        let extents_cache = hb_thread_data.glyph_extents_for_font(key);
+        let scale = size / (font_data.metrics.units_per_em as f32);
+        let synthesis = fragment.synthesis;
+        let skew = synthesis.skew.to_radians().tan();
+        // The HarfBuzz font is only needed for glyphs that aren't cached.
+        let mut hb_font: *mut hb_font_t = ptr::null_mut();
+        let mut bounds: Option<RectF> = None;
+        for glyph in &fragment.glyphs {
+            let extents = *extents_cache
+                .entry(glyph.glyph_id)
+                .or_insert_with(|| unsafe {
+                    if hb_font.is_null() {
+                        hb_font = hb_font_create(font_data.hb_face.hb_face);
+                        set_variations(hb_font, &fragment.variations);
+                    }
+                    glyph_extents(hb_font, glyph.glyph_id)
+                });
+            let mut rect = match extents {
+                Some(extents) => extents.to_f32() * scale,
+                None => continue,
+            };
+            if synthesis.embolden != 0.0 {
+                rect = rect.dilate(0.5 * synthesis.embolden);
+            }
+            if skew != 0.0 {
+                // Skewing shifts x in proportion to y.
+                let (bottom, top) = (rect.min_y() * skew, rect.max_y() * skew);
+                rect = RectF::from_points(
+                    rect.origin() + vec2f(bottom.min(top), 0.0),
+                    rect.lower_right() + vec2f(bottom.max(top), 0.0),
+                );
+            }
+            if fragment.orientation == RunOrientation::Sideways {
+                let (a, b) = (
+                    rotate_clockwise(rect.origin()),
+                    rotate_clockwise(rect.lower_right()),
+                );
+                rect = RectF::from_points(a.min(b), a.max(b));
+            }
+            let rect = rect + glyph.offset;
+            bounds = Some(bounds.map_or(rect, |bounds| bounds.union_rect(rect)));
+        }
+        if !hb_font.is_null() {
+            unsafe {
+                hb_font_destroy(hb_font);
+            }
+        }
+        bounds
+    })
+}
+
+// The ink extents of a glyph in font units, with y up.
+unsafe fn glyph_extents(hb_font: *mut hb_font_t, glyph_id: u32) -> Option<RectI> {
+    let mut extents = hb_glyph_extents_t {
+        x_bearing: 0,
+        y_bearing: 0,
+        width: 0,
+        height: 0,
+    };
+    if hb_font_get_glyph_extents(hb_font, glyph_id, &mut extents) == 0
+        || extents.width == 0
+        || extents.height == 0
+    {
+        return None;
+    }
+    // The y bearing is the top of the glyph, and the height is negative.
+    let a = vec2i(extents.x_bearing, extents.y_bearing);
+    let b = a + vec2i(extents.width, extents.height);
+    Some(RectI::from_points(a.min(b), a.max(b)))
+}
+
+// Letter spacing above this many em disables ligatures, as in Minikin.
+const LIGATURE_SPACING_LIMIT: f32 = 0.03;
+
+/// Whether letter spacing can be applied to the script. Scripts in which
+/// letters connect to each other are excluded; this is the list from Minikin.
+pub(crate) fn allows_letter_spacing(script: hb_script_t) -> bool {
+    !matches!(
+        script,
+        HB_SCRIPT_ARABIC
+            | HB_SCRIPT_NKO
+            | HB_SCRIPT_PSALTER_PAHLAVI
+            | HB_SCRIPT_MANDAIC
+            | HB_SCRIPT_MONGOLIAN
+            | HB_SCRIPT_PHAGS_PA
+            | HB_SCRIPT_DEVANAGARI
+            | HB_SCRIPT_BENGALI
+            | HB_SCRIPT_GURMUKHI
+            | HB_SCRIPT_MODI
+            | HB_SCRIPT_SHARADA
+            | HB_SCRIPT_SYLOTI_NAGRI
+            | HB_SCRIPT_TIRHUTA
+            | HB_SCRIPT_OGHAM
+    )
+}
+
+/// Whether the character separates words, for the purpose of word spacing.
+/// These are the word-separator characters of CSS Text.
+pub(crate) fn is_word_separator(c: char) -> bool {
+    matches!(
+        c,
+        '\u{20}' | '\u{a0}' | '\u{1361}' | '\u{10100}' | '\u{10101}' | '\u{1039f}' | '\u{1091f}'
+    )
+}
+
+// Add letter spacing to each cluster that has an advance, and word spacing to
+// word separators, returning the total added. Letter spacing is split evenly
+// between the two sides of the cluster. `text` is the text of the fragment.
+fn apply_spacing(
+    glyphs: &mut [FragmentGlyph],
+    text: &str,
+    letter_spacing: Vector2F,
+    word_spacing: Vector2F,
+) -> Vector2F {
+    let mut added = Vector2F::zero();
+    if letter_spacing == Vector2F::zero() && word_spacing == Vector2F::zero() {
+        return added;
+    }
+    let mut i = 0;
+    while i < glyphs.len() {
+        let cluster = glyphs[i].cluster;
+        let mut j = i;
+        let mut advance = Vector2F::zero();
+        while j < glyphs.len() && glyphs[j].cluster == cluster {
+            advance += glyphs[j].advance;
+            j += 1;
+        }
+        let mut spacing = Vector2F::zero();
+        let mut shift = added;
+        if advance != Vector2F::zero() {
+            spacing += letter_spacing;
+            shift += letter_spacing * 0.5;
+        }
+        if text[cluster as usize..]
+            .chars()
+            .next()
+            .is_some_and(is_word_separator)
+        {
+            spacing += word_spacing;
+        }
+        for glyph in &mut glyphs[i..j] {
+            glyph.offset += shift;
+        }
+        glyphs[j - 1].advance += spacing;
+        added += spacing;
+        i = j;
+    }
+    added
+}
+
+// Convert feature settings for shaping the given range of the text, where
+// cluster values are relative to the start of the range.
+fn hb_features(features: &[FontFeature], range: Range<usize>) -> Vec<hb_feature_t> {
+    features
+        .iter()
+        .filter_map(|feature| {
+            let (start, end) = match &feature.range {
+                None => (0, u32::MAX),
+                Some(r) => {
+                    let start = r.start.max(range.start);
+                    let end = r.end.min(range.end);
+                    if start >= end {
+                        return None;
+                    }
+                    ((start - range.start) as u32, (end - range.start) as u32)
+                }
+            };
+            Some(hb_feature_t {
+                tag: u32::from_be_bytes(feature.tag),
+                value: feature.value,
+                start,
+                end,
+            })
+        })
+        .collect()
+}
+
+/// The instance of a variable font selected by the style, with a value for
+/// every axis of the font, and the faux bold and italic needed for it.
+pub(crate) fn font_instance(style: &TextStyle, font: &FontRef) -> (Vec<FontVariation>, Synthesis) {
+    instance_for_data(style, &font_data(font))
+}
+
+fn instance_for_data(style: &TextStyle, font_data: &FontData) -> (Vec<FontVariation>, Synthesis) {
+    let variations = resolve_axes(style, &font_data.axes);
+    let synthesis = Synthesis::for_font(
+        &style.properties,
+        &font_data.properties,
+        &variations,
+        style.size,
+    );
+    (variations, synthesis)
+}
+
+// The value of each axis selected by the style. Settings are clamped to the
+// range of the axis, and settings for other axes are ignored.
+fn resolve_axes(style: &TextStyle, axes: &[VariationAxis]) -> Vec<FontVariation> {
+    axes.iter()
+        .map(|axis| {
+            let value = style
+                .variations
+                .iter()
+                .rev()
+                .find(|variation| variation.tag == axis.tag)
+                .map(|variation| variation.value)
+                .or_else(|| {
+                    if style.auto_optical_size && &axis.tag == b"opsz" {
+                        Some(style.size)
+                    } else {
+                        None
+                    }
+                })
+                .unwrap_or(axis.default_value);
+            FontVariation {
+                tag: axis.tag,
+                value: value.max(axis.min_value).min(axis.max_value),
+            }
+        })
+        .collect()
+}
+
+unsafe fn set_variations(hb_font: *mut hb_font_t, variations: &[FontVariation]) {
+    if variations.is_empty() {
+        return;
+    }
+    let hb_variations: Vec<hb_variation_t> = variations
+        .iter()
+        .map(|variation| hb_variation_t {
+            tag: u32::from_be_bytes(variation.tag),
+            value: variation.value,
+        })
+        .collect();
+    hb_font_set_variations(hb_font, hb_variations.as_ptr(), hb_variations.len() as u32);
+}
+
+// Rotate a vector 90 degrees clockwise (in y-up coordinates).
+fn rotate_clockwise(v: Vector2F) -> Vector2F {
+    vec2f(v.y(), -v.x())
+}
+
 #[allow(unused)]
 fn float_to_fixed(f: f32) -> i32 {
     (f * 65536.0 + 0.5).floor() as i32
@@ -212,3 +620,300 @@ unsafe extern "C" fn font_table_func(
     unimplemented!()
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use font_kit::family_name::FamilyName;
+    use font_kit::properties::Properties;
+    use font_kit::source::SystemSource;
+    use harfbuzz::sys::{hb_script_t, HB_SCRIPT_ARABIC, HB_SCRIPT_COMMON, HB_SCRIPT_LATIN};
+    use pathfinder_geometry::vector::{vec2f, Vector2F};
+
+    use std::rc::Rc;
+
+    use super::{apply_spacing, font_data, hb_features, layout_fragment, resolve_axes};
+    use crate::ot::VariationAxis;
+    use crate::session::{FragmentGlyph, LayoutFragment, Segment};
+    use crate::{FontFeature, FontRef, FontVariation, RunOrientation, TextStyle};
+
+    fn font() -> FontRef {
+        let font = SystemSource::new()
+            .select_best_match(&[FamilyName::SansSerif], &Properties::new())
+            .unwrap()
+            .load()
+            .unwrap();
+        FontRef::new(font)
+    }
+
+    // Shape the whole text as one fragment.
+    fn shape(
+        style: &TextStyle,
+        text: &str,
+        script: hb_script_t,
+        level: u8,
+        orientation: RunOrientation,
+    ) -> LayoutFragment {
+        let segment = Segment {
+            range: 0..text.len(),
+            level,
+            script,
+            orientation,
+            locale: None,
+            style: 0,
+            placeholder: None,
+        };
+        layout_fragment(style, &font(), &segment, text, 0..text.len())
+    }
+
+    #[test]
+    fn upright_runs_advance_down() {
+        let style = TextStyle::new(16.0);
+        let fragment = shape(&style, "§©", HB_SCRIPT_COMMON, 0, RunOrientation::Upright);
+        assert_eq!(fragment.glyphs.len(), 2);
+        assert_eq!(fragment.advance.x(), 0.0);
+        assert!(fragment.advance.y() < 0.0);
+        for glyph in &fragment.glyphs {
+            assert_eq!(glyph.advance.x(), 0.0);
+            assert!(glyph.advance.y() < 0.0);
+        }
+        // The second glyph is placed below the first.
+        assert!(fragment.glyphs[1].offset.y() < fragment.glyphs[0].offset.y());
+    }
+
+    #[test]
+    fn sideways_runs_are_rotated() {
+        let style = TextStyle::new(16.0);
+        let horizontal = shape(&style, "ab", HB_SCRIPT_LATIN, 0, RunOrientation::Horizontal);
+        let sideways = shape(&style, "ab", HB_SCRIPT_LATIN, 0, RunOrientation::Sideways);
+        let rotate = |v: Vector2F| vec2f(v.y(), -v.x());
+        assert_eq!(sideways.advance, rotate(horizontal.advance));
+        for (sideways, horizontal) in sideways.glyphs.iter().zip(&horizontal.glyphs) {
+            assert_eq!(sideways.glyph_id, horizontal.glyph_id);
+            assert_eq!(sideways.advance, rotate(horizontal.advance));
+        }
+        // Glyphs follow each other down the line, centered on it.
+        let (first, second) = (&sideways.glyphs[0], &sideways.glyphs[1]);
+        assert_eq!(first.offset.y(), 0.0);
+        assert_eq!(second.offset.y(), first.advance.y());
+        assert_eq!(first.offset.x(), second.offset.x());
+        assert!(first.offset.x() < 0.0);
+    }
+
+    #[test]
+    fn feature_ranges_are_relative_to_the_shaped_range() {
+        let features = [
+            FontFeature::with_range(b"liga", 0, 4..6),
+            FontFeature::new(b"kern", 0),
+            FontFeature::with_range(b"smcp", 1, 0..2),
+            FontFeature::with_range(b"onum", 1, 6..10),
+        ];
+        let hb = hb_features(&features, 3..8);
+        let settings: Vec<_> = hb
+            .iter()
+            .map(|feature| (feature.tag.to_be_bytes(), feature.start, feature.end))
+            .collect();
+        assert_eq!(
+            settings,
+            vec![(*b"liga", 1, 3), (*b"kern", 0, u32::MAX), (*b"onum", 3, 5)]
+        );
+    }
+
+    #[test]
+    fn ranged_features_apply_only_inside_their_range() {
+        let text = "fi fi";
+        let ligated = |features: Vec<FontFeature>| {
+            let style = TextStyle {
+                features,
+                ..TextStyle::new(16.0)
+            };
+            let segment = Segment {
+                range: 0..text.len(),
+                level: 0,
+                script: HB_SCRIPT_LATIN,
+                orientation: RunOrientation::Horizontal,
+                locale: None,
+                style: 0,
+                placeholder: None,
+            };
+            // Shape the second word on its own.
+            layout_fragment(&style, &font(), &segment, text, 3..5)
+                .glyphs
+                .len()
+                == 1
+        };
+        assert!(ligated(Vec::new()));
+        assert!(!ligated(vec![FontFeature::with_range(b"liga", 0, 3..5)]));
+        assert!(ligated(vec![FontFeature::with_range(b"liga", 0, 0..2)]));
+        assert!(!ligated(vec![FontFeature::with_range(b"liga", 0, 0..4)]));
+    }
+
+    fn glyph(cluster: u32, offset: f32, advance: f32) -> FragmentGlyph {
+        FragmentGlyph {
+            cluster,
+            glyph_id: 0,
+            offset: vec2f(offset, 0.0),
+            advance: vec2f(advance, 0.0),
+            unsafe_to_break: false,
+        }
+    }
+
+    #[test]
+    fn letter_spacing_is_split_around_clusters() {
+        // "e" with a combining mark, a space, and "x".
+        let mut glyphs = vec![
+            glyph(0, 0.0, 10.0),
+            glyph(0, 2.0, 0.0),
+            glyph(3, 10.0, 5.0),
+            glyph(4, 15.0, 10.0),
+        ];
+        let added = apply_spacing(&mut glyphs, "e\u{301} x", vec2f(2.0, 0.0), vec2f(4.0, 0.0));
+        assert_eq!(added, vec2f(10.0, 0.0));
+        // Half the letter spacing goes before each cluster, and the other
+        // half after it, along with any word spacing.
+        let offsets: Vec<f32> = glyphs.iter().map(|glyph| glyph.offset.x()).collect();
+        let advances: Vec<f32> = glyphs.iter().map(|glyph| glyph.advance.x()).collect();
+        assert_eq!(offsets, vec![1.0, 3.0, 13.0, 24.0]);
+        assert_eq!(advances, vec![10.0, 2.0, 11.0, 12.0]);
+    }
+
+    #[test]
+    fn letter_spacing_skips_clusters_without_advance() {
+        let mut glyphs = vec![glyph(0, 0.0, 10.0), glyph(1, 10.0, 0.0)];
+        let added = apply_spacing(&mut glyphs, "a\u{200b}", vec2f(2.0, 0.0), Vector2F::zero());
+        assert_eq!(added, vec2f(2.0, 0.0));
+        assert_eq!(glyphs[1].offset.x(), 12.0);
+        assert_eq!(glyphs[1].advance.x(), 0.0);
+    }
+
+    fn spaced(
+        text: &str,
+        script: hb_script_t,
+        level: u8,
+        letter: f32,
+        word: f32,
+    ) -> LayoutFragment {
+        let style = TextStyle {
+            letter_spacing: letter,
+            word_spacing: word,
+            ..TextStyle::new(16.0)
+        };
+        shape(&style, text, script, level, RunOrientation::Horizontal)
+    }
+
+    #[test]
+    fn word_spacing_is_added_to_spaces() {
+        let plain = spaced("a b c", HB_SCRIPT_LATIN, 0, 0.0, 0.0);
+        let fragment = spaced("a b c", HB_SCRIPT_LATIN, 0, 0.0, 0.25);
+        assert_eq!(fragment.advance.x(), plain.advance.x() + 8.0);
+        for (glyph, plain) in fragment.glyphs.iter().zip(&plain.glyphs) {
+            let extra = if glyph.cluster == 1 || glyph.cluster == 3 {
+                4.0
+            } else {
+                0.0
+            };
+            assert_eq!(glyph.advance.x(), plain.advance.x() + extra);
+        }
+    }
+
+    #[test]
+    fn letter_spacing_disables_ligatures() {
+        let glyph_count = |letter_spacing, features: Vec<FontFeature>| {
+            let style = TextStyle {
+                letter_spacing,
+                features,
+                ..TextStyle::new(16.0)
+            };
+            shape(&style, "fi", HB_SCRIPT_LATIN, 0, RunOrientation::Horizontal)
+                .glyphs
+                .len()
+        };
+        assert_eq!(glyph_count(0.0, Vec::new()), 1);
+        assert_eq!(glyph_count(0.03, Vec::new()), 1);
+        assert_eq!(glyph_count(0.05, Vec::new()), 2);
+        assert_eq!(glyph_count(-0.05, Vec::new()), 2);
+        // Explicit settings take precedence.
+        assert_eq!(glyph_count(0.05, vec![FontFeature::new(b"liga", 1)]), 1);
+    }
+
+    #[test]
+    fn letter_spacing_skips_cursive_scripts() {
+        let plain = spaced("سلام", HB_SCRIPT_ARABIC, 1, 0.0, 0.0);
+        let fragment = spaced("سلام", HB_SCRIPT_ARABIC, 1, 0.1, 0.0);
+        assert_eq!(fragment.advance, plain.advance);
+        let latin = spaced("abcd", HB_SCRIPT_LATIN, 0, 0.1, 0.0);
+        let plain_latin = spaced("abcd", HB_SCRIPT_LATIN, 0, 0.0, 0.0);
+        assert!((latin.advance.x() - plain_latin.advance.x() - 6.4).abs() < 1e-3);
+    }
+
+    fn axes() -> Vec<VariationAxis> {
+        let axis = |tag: &[u8; 4], min_value, default_value, max_value| VariationAxis {
+            tag: *tag,
+            min_value,
+            default_value,
+            max_value,
+        };
+        vec![
+            axis(b"wght", 100.0, 400.0, 900.0),
+            axis(b"opsz", 8.0, 14.0, 144.0),
+        ]
+    }
+
+    fn instance(style: &TextStyle) -> Vec<([u8; 4], f32)> {
+        resolve_axes(style, &axes())
+            .iter()
+            .map(|variation| (variation.tag, variation.value))
+            .collect()
+    }
+
+    fn with_variations(variations: &[(&[u8; 4], f32)]) -> TextStyle {
+        TextStyle {
+            variations: variations
+                .iter()
+                .map(|&(tag, value)| FontVariation::new(tag, value))
+                .collect(),
+            ..TextStyle::new(24.0)
+        }
+    }
+
+    #[test]
+    fn variations_default_to_the_axis_default() {
+        let style = with_variations(&[]);
+        assert_eq!(instance(&style), vec![(*b"wght", 400.0), (*b"opsz", 14.0)]);
+    }
+
+    #[test]
+    fn variations_are_clamped_to_the_axis() {
+        let style = with_variations(&[(b"wght", 1000.0), (b"opsz", 2.0)]);
+        assert_eq!(instance(&style), vec![(*b"wght", 900.0), (*b"opsz", 8.0)]);
+        // The last setting for an axis wins.
+        let style = with_variations(&[(b"wght", 50.0), (b"wght", 700.0)]);
+        assert_eq!(instance(&style), vec![(*b"wght", 700.0), (*b"opsz", 14.0)]);
+    }
+
+    #[test]
+    fn unknown_axes_are_ignored() {
+        let style = with_variations(&[(b"wdth", 75.0), (b"wght", 300.0)]);
+        assert_eq!(instance(&style), vec![(*b"wght", 300.0), (*b"opsz", 14.0)]);
+        assert!(resolve_axes(&style, &[]).is_empty());
+    }
+
+    #[test]
+    fn automatic_optical_size() {
+        let mut style = with_variations(&[]);
+        style.auto_optical_size = true;
+        assert_eq!(instance(&style), vec![(*b"wght", 400.0), (*b"opsz", 24.0)]);
+        style.size = 200.0;
+        assert_eq!(instance(&style), vec![(*b"wght", 400.0), (*b"opsz", 144.0)]);
+        // An explicit setting takes precedence.
+        style.variations.push(FontVariation::new(b"opsz", 10.0));
+        assert_eq!(instance(&style), vec![(*b"wght", 400.0), (*b"opsz", 10.0)]);
+    }
+
+    #[test]
+    fn font_data_is_shared_between_loads() {
+        let (a, b) = (font(), font());
+        assert!(Rc::ptr_eq(&font_data(&a), &font_data(&b)));
+        assert!(font_data(&a).axes.is_empty());
+        assert_eq!(font_data(&a).properties, a.font.properties());
+    }
+}
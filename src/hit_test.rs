@@ -0,0 +1,232 @@
+//! Caret positions, for hit testing and cursor placement.
+
+use pathfinder_geometry::vector::Vector2F;
+use unicode_segmentation::GraphemeCursor;
+
+use crate::session::LayoutFragment;
+
+/// Call `f` with each grapheme boundary in the fragment, including both ends,
+/// and the caret position for it relative to the fragment origin.
+///
+/// Offsets are relative to the session text. Boundaries are visited in
+/// visual order. When a cluster, such as a ligature, contains several
+/// graphemes, its advance is divided evenly between them. An inline object
+/// only has boundaries at its ends.
+pub(crate) fn fragment_carets(
+    fragment: &LayoutFragment,
+    text: &str,
+    mut f: impl FnMut(usize, Vector2F),
+) {
+    let rtl = fragment.is_rtl();
+    let glyphs = &fragment.glyphs;
+    let start = fragment.substr_start;
+    let end = start + fragment.substr_len;
+    if rtl {
+        f(end, Vector2F::zero());
+    } else {
+        f(start, Vector2F::zero());
+    }
+    let mut pen = Vector2F::zero();
+    let mut i = 0;
+    while i < glyphs.len() {
+        // Glyphs of a cluster are contiguous, in both directions.
+        let cluster = glyphs[i].cluster;
+        let mut cluster_advance = Vector2F::zero();
+        let mut j = i;
+        while j < glyphs.len() && glyphs[j].cluster == cluster {
+            cluster_advance += glyphs[j].advance;
+            j += 1;
+        }
+        // The logically next cluster is stored after this one in LTR text,
+        // before it in RTL text.
+        let next = if rtl {
+            i.checked_sub(1)
+        } else {
+            glyphs.get(j).map(|_| j)
+        };
+        let cluster_start = start + cluster as usize;
+        let cluster_end = next.map_or(end, |ix| start + glyphs[ix].cluster as usize);
+        // Positions of the logical start and end of the cluster.
+        let (from, to) = if rtl {
+            (pen + cluster_advance, pen)
+        } else {
+            (pen, pen + cluster_advance)
+        };
+        let is_boundary = |offset: &usize| is_grapheme_boundary(text, *offset);
+        let inner = if fragment.placeholder.is_some() {
+            cluster_end..cluster_end
+        } else {
+            cluster_start + 1..cluster_end
+        };
+        let parts = (inner.clone().filter(is_boundary).count() + 1) as f32;
+        let mut emit = |n: usize, offset: usize| f(offset, from + (to - from) * (n as f32 / parts));
+        // The fragment start is emitted separately. Other cluster starts are
+        // skipped when they're not grapheme boundaries.
+        let emit_start = cluster_start != start && is_boundary(&cluster_start);
+        if rtl {
+            let mut n = parts as usize;
+            for offset in inner.rev().filter(is_boundary) {
+                n -= 1;
+                emit(n, offset);
+            }
+            if emit_start {
+                emit(0, cluster_start);
+            }
+        } else {
+            if emit_start {
+                emit(0, cluster_start);
+            }
+            for (n, offset) in inner.filter(is_boundary).enumerate() {
+                emit(n + 1, offset);
+            }
+        }
+        pen += cluster_advance;
+        i = j;
+    }
+    if rtl {
+        f(start, fragment.advance);
+    } else {
+        f(end, fragment.advance);
+    }
+}
+
+pub(crate) fn is_grapheme_boundary(text: &str, offset: usize) -> bool {
+    text.is_char_boundary(offset)
+        && GraphemeCursor::new(offset, text.len(), true)
+            .is_boundary(text, 0)
+            .unwrap_or(true)
+}
+
+/// The caret position for an offset within the fragment, relative to the
+/// fragment origin. Offsets within a grapheme are moved to its start, or to
+/// its end if `round_up` is set.
+pub(crate) fn fragment_caret(
+    fragment: &LayoutFragment,
+    text: &str,
+    offset: usize,
+    round_up: bool,
+) -> Vector2F {
+    let mut caret: Option<(usize, Vector2F)> = None;
+    fragment_carets(fragment, text, |caret_offset, position| {
+        let valid = if round_up {
+            caret_offset >= offset
+        } else {
+            caret_offset <= offset
+        };
+        let better = match caret {
+            None => true,
+            Some((best, _)) if round_up => caret_offset < best,
+            Some((best, _)) => caret_offset > best,
+        };
+        if valid && better {
+            caret = Some((caret_offset, position));
+        }
+    });
+    caret.map_or(Vector2F::zero(), |(_, position)| position)
+}
+
+#[cfg(test)]
+mod tests {
+    use font_kit::family_name::FamilyName;
+    use font_kit::properties::Properties;
+    use font_kit::source::SystemSource;
+
+    use crate::{FontCollection, FontFamily, LayoutSession, TextStyle, WritingMode};
+
+    fn session(text: &str, style: &TextStyle) -> LayoutSession<String> {
+        let font = SystemSource::new()
+            .select_best_match(&[FamilyName::SansSerif], &Properties::new())
+            .unwrap()
+            .load()
+            .unwrap();
+        let mut collection = FontCollection::new();
+        collection.add_family(FontFamily::new_from_font(font));
+        LayoutSession::create(text.to_string(), style, &collection)
+    }
+
+    // Check that each offset maps to a caret that maps back to it.
+    fn assert_round_trip(session: &LayoutSession<String>, offsets: &[usize]) {
+        for &offset in offsets {
+            let advance = session.advance_for_offset(offset);
+            assert_eq!(
+                session.offset_for_advance(advance),
+                offset,
+                "offset {} in {:?}, at {}",
+                offset,
+                session.text(),
+                advance
+            );
+        }
+    }
+
+    #[test]
+    fn rtl_carets() {
+        let session = session("שלום", &TextStyle::new(16.0));
+        assert_round_trip(&session, &[0, 2, 4, 6, 8]);
+        // The text starts on the right.
+        let carets: Vec<f32> = [0, 2, 4, 6, 8]
+            .iter()
+            .map(|&offset| session.advance_for_offset(offset))
+            .collect();
+        assert!(carets.windows(2).all(|pair| pair[0] > pair[1]));
+        assert_eq!(carets[4], 0.0);
+    }
+
+    #[test]
+    fn ligature_carets() {
+        let session = session("office", &TextStyle::new(16.0));
+        // "ffi" is a single glyph, divided evenly between its letters.
+        assert_eq!(session.iter_all().next().unwrap().glyphs().count(), 4);
+        assert_round_trip(&session, &[0, 1, 2, 3, 4, 5, 6]);
+        let caret = |offset| session.advance_for_offset(offset);
+        let width = caret(2) - caret(1);
+        assert!(width > 0.0);
+        assert!((caret(3) - caret(2) - width).abs() < 1e-3);
+        assert!((caret(4) - caret(3) - width).abs() < 1e-3);
+    }
+
+    #[test]
+    fn combining_mark_carets() {
+        let session = session("e\u{301}x", &TextStyle::new(16.0));
+        assert_round_trip(&session, &[0, 3, 4]);
+        // Offsets within the grapheme go to its start.
+        assert_eq!(session.advance_for_offset(1), session.advance_for_offset(0));
+        assert_eq!(session.advance_for_offset(2), session.advance_for_offset(0));
+        assert!(session.advance_for_offset(3) > 0.0);
+    }
+
+    #[test]
+    fn vertical_carets() {
+        let style = TextStyle {
+            writing_mode: WritingMode::Vertical,
+            ..TextStyle::new(16.0)
+        };
+        // Sideways and upright runs.
+        let session = session("ab§c", &style);
+        assert_round_trip(&session, &[0, 1, 2, 4, 5]);
+        // Positions are y coordinates, going down the line.
+        let carets: Vec<f32> = [0, 1, 2, 4, 5]
+            .iter()
+            .map(|&offset| session.advance_for_offset(offset))
+            .collect();
+        assert!(carets.windows(2).all(|pair| pair[0] > pair[1]));
+        assert_eq!(session.advance_for_offset(3), carets[2]);
+    }
+
+    #[test]
+    fn bidi_boundary_carets() {
+        // Runs "abc ", "שלום" (right to left) and " def".
+        let session = session("abc שלום def", &TextStyle::new(16.0));
+        assert_round_trip(&session, &[0, 1, 2, 3, 4, 6, 8, 10, 13, 14, 15, 16]);
+        // The start of the Hebrew text is on its right, where " def" starts
+        // too. Offset 12 is placed at the start of " def", which contains
+        // the text after it, and that position maps back to offset 4.
+        let right_edge = session.advance_for_offset(4);
+        assert_eq!(session.advance_for_offset(12), right_edge);
+        assert_eq!(session.offset_for_advance(right_edge), 4);
+        // The left edge of the Hebrew text is the end of "abc ".
+        let left_edge = session.iter_all().next().unwrap().advance().x();
+        assert!(left_edge < session.advance_for_offset(10));
+        assert_eq!(session.offset_for_advance(left_edge), 4);
+    }
+}
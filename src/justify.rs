@@ -0,0 +1,538 @@
+//! Justification of a line by expanding the space between words or
+//! characters, or by inserting kashida in Arabic text, without reshaping.
+
+use std::ops::Range;
+
+use harfbuzz::sys::{
+    hb_script_t, HB_SCRIPT_ARABIC, HB_SCRIPT_BOPOMOFO, HB_SCRIPT_HAN, HB_SCRIPT_HIRAGANA,
+    HB_SCRIPT_KATAKANA,
+};
+use pathfinder_geometry::vector::{vec2f, Vector2F};
+
+use crate::hb_layout::{allows_letter_spacing, is_word_separator};
+use crate::session::{FragmentGlyph, LayoutFragment};
+use crate::{RunOrientation, TextStyle};
+
+// Where extra space can go, in order of preference.
+#[derive(Clone, Copy, PartialEq)]
+enum Expansion {
+    // At word separators, between the characters of CJK text, which doesn't
+    // separate words with spaces, and with kashida within Arabic words.
+    InterWord,
+    // Between all characters, as a last resort for text without word
+    // separators. Scripts that can't be letter-spaced are left alone.
+    InterCharacter,
+}
+
+/// Expand the fragments of a line, given in logical order, so that the line
+/// is `width` long. Trailing whitespace is not expanded and doesn't count
+/// towards the width.
+///
+/// Lines that are already wider, or that have no place to add space, are
+/// left unchanged.
+pub(crate) fn justify_fragments(
+    fragments: &mut [LayoutFragment],
+    text: &str,
+    styles: &[TextStyle],
+    width: f32,
+) {
+    let text_end = match fragments.last() {
+        Some(fragment) => fragment.substr_start + fragment.substr_len,
+        None => return,
+    };
+    let start = fragments[0].substr_start;
+    let content_end = start + text[start..text_end].trim_end().len();
+    let mut current_width = 0.0;
+    for fragment in fragments.iter() {
+        for_each_cluster(fragment, |glyphs, cluster_start, _| {
+            if cluster_start < content_end {
+                current_width += line_length(fragment, glyph_advance(fragment, glyphs));
+            }
+        });
+    }
+    let find_stretches = |expansion| -> Vec<Stretches> {
+        fragments
+            .iter()
+            .map(|fragment| {
+                let size = styles[fragment.style].size;
+                Stretches::new(fragment, text, size, content_end, expansion)
+            })
+            .collect()
+    };
+    let mut stretches = find_stretches(Expansion::InterWord);
+    if stretches.iter().all(|stretches| stretches.count == 0) {
+        stretches = find_stretches(Expansion::InterCharacter);
+    }
+    let count: usize = stretches.iter().map(|stretches| stretches.count).sum();
+    let extra = width - current_width;
+    if count == 0 || extra <= 0.0 {
+        return;
+    }
+    let per_stretch = extra / count as f32;
+    for (fragment, stretches) in fragments.iter_mut().zip(stretches) {
+        if stretches.count != 0 {
+            stretch_fragment(fragment, &stretches, per_stretch);
+        }
+    }
+}
+
+// The places in a fragment where space can be added, by glyph index.
+struct Stretches {
+    // Add space to the advance of the glyph.
+    widen: Vec<bool>,
+    // Insert tatweels before the glyph.
+    kashida: Vec<bool>,
+    // The tatweel glyph and its advance, for fragments with kashida.
+    tatweel: Option<(u32, f32)>,
+    count: usize,
+}
+
+impl Stretches {
+    fn new(
+        fragment: &LayoutFragment,
+        text: &str,
+        size: f32,
+        content_end: usize,
+        expansion: Expansion,
+    ) -> Stretches {
+        let mut widen = vec![false; fragment.glyphs.len()];
+        let mut kashida = vec![false; fragment.glyphs.len()];
+        let mut count = 0;
+        for_each_cluster(fragment, |glyphs, cluster_start, cluster_end| {
+            if is_opportunity(
+                fragment,
+                text,
+                cluster_start,
+                cluster_end,
+                content_end,
+                expansion,
+            ) {
+                widen[glyphs.end - 1] = true;
+                count += 1;
+            }
+        });
+        let tatweel = if expansion == Expansion::InterWord {
+            tatweel(fragment, size)
+        } else {
+            None
+        };
+        if tatweel.is_some() {
+            for offset in kashida_points(fragment, text, content_end) {
+                // Glyphs are in visual order, so the text after the point
+                // comes first.
+                let relative = (offset - fragment.substr_start) as u32;
+                let ix = fragment
+                    .glyphs
+                    .iter()
+                    .take_while(|glyph| glyph.cluster >= relative)
+                    .count();
+                kashida[ix] = true;
+                count += 1;
+            }
+        }
+        Stretches {
+            widen,
+            kashida,
+            tatweel,
+            count,
+        }
+    }
+}
+
+// Add `amount` of space at each of the stretches of the fragment.
+fn stretch_fragment(fragment: &mut LayoutFragment, stretches: &Stretches, amount: f32) {
+    let direction = line_direction(fragment);
+    let mut glyphs = Vec::with_capacity(fragment.glyphs.len());
+    // The pen position before stretching, and the space added so far.
+    let mut pen = Vector2F::zero();
+    let mut shift = Vector2F::zero();
+    for (i, glyph) in fragment.glyphs.iter().enumerate() {
+        if let (true, Some((tatweel_id, tatweel_advance))) =
+            (stretches.kashida[i], stretches.tatweel)
+        {
+            // Tatweels overlap as needed to cover the space exactly.
+            let n = (amount / tatweel_advance).ceil().max(1.0);
+            let step = direction * (amount / n);
+            for _ in 0..n as usize {
+                glyphs.push(FragmentGlyph {
+                    cluster: glyph.cluster,
+                    glyph_id: tatweel_id,
+                    offset: pen + shift,
+                    advance: step,
+                    unsafe_to_break: true,
+                });
+                shift += step;
+            }
+        }
+        let mut glyph = FragmentGlyph {
+            offset: glyph.offset + shift,
+            ..glyph.clone()
+        };
+        pen += glyph.advance;
+        if stretches.widen[i] {
+            glyph.advance += direction * amount;
+            shift += direction * amount;
+        }
+        glyphs.push(glyph);
+    }
+    fragment.glyphs = glyphs;
+    fragment.advance += shift;
+}
+
+// The tatweel glyph of the fragment's font and its advance, if kashida can be
+// used in the fragment. This is only done for horizontal Arabic text.
+fn tatweel(fragment: &LayoutFragment, size: f32) -> Option<(u32, f32)> {
+    if fragment.script != HB_SCRIPT_ARABIC
+        || fragment.orientation != RunOrientation::Horizontal
+        || !fragment.is_rtl()
+    {
+        return None;
+    }
+    let font = &fragment.font.font;
+    let glyph_id = font.glyph_for_char(TATWEEL)?;
+    let advance = font.advance(glyph_id).ok()?.x();
+    let advance = advance * size / (font.metrics().units_per_em as f32);
+    if advance > 0.0 {
+        Some((glyph_id, advance))
+    } else {
+        None
+    }
+}
+
+const TATWEEL: char = '\u{640}';
+
+// The offsets in the text where kashida can be inserted: between two letters
+// that join, where the second starts a cluster (so ligatures are not split).
+// Only one point is used per word, the last one, which is where kashida
+// usually look best.
+fn kashida_points(fragment: &LayoutFragment, text: &str, content_end: usize) -> Vec<usize> {
+    let start = fragment.substr_start;
+    let end = content_end.min(start + fragment.substr_len);
+    let mut points = Vec::new();
+    let mut word_point = None;
+    let mut previous = Joining::None;
+    for (i, c) in text[start..end.max(start)].char_indices() {
+        let offset = start + i;
+        let joining = arabic_joining(c);
+        if is_word_separator(c) {
+            points.extend(word_point.take());
+            previous = Joining::None;
+            continue;
+        }
+        if joining == Joining::Transparent {
+            continue;
+        }
+        let is_cluster_start = fragment
+            .glyphs
+            .iter()
+            .any(|glyph| glyph.cluster as usize == i);
+        if previous == Joining::Dual
+            && (joining == Joining::Dual || joining == Joining::Right)
+            && is_cluster_start
+        {
+            word_point = Some(offset);
+        }
+        previous = joining;
+    }
+    points.extend(word_point);
+    points
+}
+
+// Arabic joining types, from ArabicShaping.txt. Join-causing characters
+// such as the tatweel itself are treated as not joining.
+#[derive(Clone, Copy, PartialEq)]
+enum Joining {
+    Dual,
+    Right,
+    Transparent,
+    None,
+}
+
+// The joining type of characters in the Arabic block.
+fn arabic_joining(c: char) -> Joining {
+    match c {
+        '\u{622}'..='\u{625}'
+        | '\u{627}'
+        | '\u{629}'
+        | '\u{62f}'..='\u{632}'
+        | '\u{648}'
+        | '\u{671}'..='\u{673}'
+        | '\u{675}'..='\u{677}'
+        | '\u{688}'..='\u{699}'
+        | '\u{6c0}'
+        | '\u{6c3}'..='\u{6cb}'
+        | '\u{6cd}'
+        | '\u{6cf}'
+        | '\u{6d2}'..='\u{6d3}'
+        | '\u{6d5}'
+        | '\u{6ee}'..='\u{6ef}' => Joining::Right,
+        '\u{620}'
+        | '\u{626}'
+        | '\u{628}'
+        | '\u{62a}'..='\u{62e}'
+        | '\u{633}'..='\u{63f}'
+        | '\u{641}'..='\u{647}'
+        | '\u{649}'..='\u{64a}'
+        | '\u{66e}'..='\u{66f}'
+        | '\u{678}'..='\u{687}'
+        | '\u{69a}'..='\u{6bf}'
+        | '\u{6c1}'..='\u{6c2}'
+        | '\u{6cc}'
+        | '\u{6ce}'
+        | '\u{6d0}'..='\u{6d1}'
+        | '\u{6fa}'..='\u{6fc}'
+        | '\u{6ff}' => Joining::Dual,
+        '\u{610}'..='\u{61a}'
+        | '\u{64b}'..='\u{65f}'
+        | '\u{670}'
+        | '\u{6d6}'..='\u{6dc}'
+        | '\u{6df}'..='\u{6e4}'
+        | '\u{6e7}'..='\u{6e8}'
+        | '\u{6ea}'..='\u{6ed}' => Joining::Transparent,
+        _ => Joining::None,
+    }
+}
+
+// Call `f` with the glyph range, and the start and end in the session text,
+// of each cluster of the fragment, in the order of the glyphs.
+fn for_each_cluster(fragment: &LayoutFragment, mut f: impl FnMut(Range<usize>, usize, usize)) {
+    let glyphs = &fragment.glyphs;
+    let mut i = 0;
+    while i < glyphs.len() {
+        let mut j = i + 1;
+        while j < glyphs.len() && glyphs[j].cluster == glyphs[i].cluster {
+            j += 1;
+        }
+        let text_range = fragment.cluster_range(i);
+        f(i..j, text_range.start, text_range.end);
+        i = j;
+    }
+}
+
+fn is_opportunity(
+    fragment: &LayoutFragment,
+    text: &str,
+    cluster_start: usize,
+    cluster_end: usize,
+    content_end: usize,
+    expansion: Expansion,
+) -> bool {
+    let separator = text[cluster_start..]
+        .chars()
+        .next()
+        .is_some_and(is_word_separator);
+    if separator {
+        // Trailing whitespace is not expanded.
+        return expansion == Expansion::InterWord && cluster_start < content_end;
+    }
+    // Space goes after a character, so there must be another one after it.
+    if cluster_end >= content_end {
+        return false;
+    }
+    match expansion {
+        Expansion::InterWord => is_cjk_script(fragment.script),
+        Expansion::InterCharacter => allows_letter_spacing(fragment.script),
+    }
+}
+
+fn is_cjk_script(script: hb_script_t) -> bool {
+    matches!(
+        script,
+        HB_SCRIPT_HAN | HB_SCRIPT_HIRAGANA | HB_SCRIPT_KATAKANA | HB_SCRIPT_BOPOMOFO
+    )
+}
+
+fn glyph_advance(fragment: &LayoutFragment, glyphs: Range<usize>) -> Vector2F {
+    fragment.glyphs[glyphs]
+        .iter()
+        .fold(Vector2F::zero(), |advance, glyph| advance + glyph.advance)
+}
+
+// The unit vector in the direction the fragment advances.
+fn line_direction(fragment: &LayoutFragment) -> Vector2F {
+    if fragment.orientation == RunOrientation::Horizontal {
+        vec2f(1.0, 0.0)
+    } else {
+        vec2f(0.0, -1.0)
+    }
+}
+
+fn line_length(fragment: &LayoutFragment, advance: Vector2F) -> f32 {
+    advance.dot(line_direction(fragment))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+
+    use font_kit::family_name::FamilyName;
+    use font_kit::properties::Properties;
+    use font_kit::source::SystemSource;
+    use harfbuzz::sys::HB_SCRIPT_ARABIC;
+
+    use super::{kashida_points, TATWEEL};
+    use crate::hb_layout::layout_fragment;
+    use crate::session::{LayoutFragment, Segment};
+    use crate::{
+        FontCollection, FontFamily, FontRef, LayoutRun, LayoutSession, RunOrientation, TextStyle,
+    };
+
+    fn font() -> FontRef {
+        let font = SystemSource::new()
+            .select_best_match(&[FamilyName::SansSerif], &Properties::new())
+            .unwrap()
+            .load()
+            .unwrap();
+        FontRef::new(font)
+    }
+
+    fn session(text: &str) -> LayoutSession<String> {
+        let mut collection = FontCollection::new();
+        collection.add_family(FontFamily::new_from_font(font().font.as_ref().clone()));
+        LayoutSession::create(text.to_string(), &TextStyle::new(16.0), &collection)
+    }
+
+    // The start and advance of each cluster of the runs, in logical order.
+    fn logical_advances<'a>(runs: impl Iterator<Item = LayoutRun<'a>>) -> Vec<(usize, f32)> {
+        let mut clusters: Vec<(usize, f32)> = runs
+            .flat_map(|run| run.clusters())
+            .map(|cluster| (cluster.text_range.start, cluster.advance.x()))
+            .collect();
+        clusters.sort_by_key(|&(start, _)| start);
+        clusters
+    }
+
+    // The start of each cluster of the substring, with its advance as laid
+    // out by `iter_substr` and when justified to `width`.
+    fn cluster_advances(text: &str, range: Range<usize>, width: f32) -> Vec<(usize, f32, f32)> {
+        let mut session = session(text);
+        let plain = logical_advances(session.iter_substr(range.clone()));
+        let justified = logical_advances(session.iter_justified(range, width));
+        assert_eq!(plain.len(), justified.len());
+        plain
+            .into_iter()
+            .zip(justified)
+            .map(|((start, plain), (_, justified))| (start, plain, justified))
+            .collect()
+    }
+
+    fn total(session: &mut LayoutSession<String>, range: Range<usize>, width: f32) -> f32 {
+        session
+            .iter_justified(range, width)
+            .map(|run| run.advance().x())
+            .sum()
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn inter_word_expansion() {
+        let text = "aaa bbb ccc";
+        let width = session(text).advance_substr(0..11).x() + 10.0;
+        for (start, plain, justified) in cluster_advances(text, 0..11, width) {
+            let extra = if start == 3 || start == 7 { 5.0 } else { 0.0 };
+            assert_close(justified, plain + extra);
+        }
+        assert_close(total(&mut session(text), 0..11, width), width);
+    }
+
+    #[test]
+    fn inter_character_expansion() {
+        // Without word separators, space goes between all the characters.
+        let text = "abcd";
+        let width = session(text).advance_substr(0..4).x() + 9.0;
+        for (start, plain, justified) in cluster_advances(text, 0..4, width) {
+            let extra = if start < 3 { 3.0 } else { 0.0 };
+            assert_close(justified, plain + extra);
+        }
+        assert_close(total(&mut session(text), 0..4, width), width);
+    }
+
+    #[test]
+    fn trailing_whitespace_hangs() {
+        let text = "aaa bbb  ";
+        let mut latin = session(text);
+        let width = latin.advance_substr(0..7).x() + 10.0;
+        let trailing = latin.advance_substr(7..9).x();
+        assert_close(total(&mut latin, 0..9, width), width + trailing);
+        for (start, plain, justified) in cluster_advances(text, 0..9, width) {
+            let extra = if start == 3 { 10.0 } else { 0.0 };
+            assert_close(justified, plain + extra);
+        }
+        // The same goes for kashida.
+        let mut arabic = session("سلام  ");
+        let width = arabic.advance_substr(0..8).x() + 10.0;
+        let trailing = arabic.advance_substr(8..10).x();
+        assert_close(total(&mut arabic, 0..10, width), width + trailing);
+    }
+
+    #[test]
+    fn wide_lines_are_unchanged() {
+        let text = "aaa bbb";
+        let width = session(text).advance_substr(0..7).x() - 5.0;
+        for (_, plain, justified) in cluster_advances(text, 0..7, width) {
+            assert_eq!(justified, plain);
+        }
+    }
+
+    // Shape right-to-left Arabic text as one fragment.
+    fn arabic(text: &str) -> LayoutFragment {
+        let segment = Segment {
+            range: 0..text.len(),
+            level: 1,
+            script: HB_SCRIPT_ARABIC,
+            orientation: RunOrientation::Horizontal,
+            locale: None,
+            style: 0,
+            placeholder: None,
+        };
+        layout_fragment(
+            &TextStyle::new(16.0),
+            &font(),
+            &segment,
+            text,
+            0..text.len(),
+        )
+    }
+
+    #[test]
+    fn kashida_points_in_joining_words() {
+        // The last joining pair of each word: "سل", since "لا" is a ligature
+        // and "ا" doesn't join to the letter after it, and "كم".
+        let text = "سلام عليكم";
+        let fragment = arabic(text);
+        assert_eq!(kashida_points(&fragment, text, text.len()), vec![2, 17]);
+        // Only the content of the line is considered.
+        assert_eq!(kashida_points(&fragment, text, 8), vec![2]);
+    }
+
+    #[test]
+    fn no_kashida_points_after_non_joining_letters() {
+        // Letters that only join to the right can't be followed by kashida.
+        let text = "ادوز ر";
+        let fragment = arabic(text);
+        assert!(kashida_points(&fragment, text, text.len()).is_empty());
+    }
+
+    #[test]
+    fn kashida_reach_the_width() {
+        let text = "سلام";
+        let mut session = session(text);
+        let plain = session.advance_substr(0..8).x();
+        let tatweel = font().font.glyph_for_char(TATWEEL).unwrap();
+        for extra in &[1.0, 10.0, 25.0] {
+            let width = plain + extra;
+            let runs: Vec<_> = session.iter_justified(0..8, width).collect();
+            let total: f32 = runs.iter().map(|run| run.advance().x()).sum();
+            assert_close(total, width);
+            let glyphs: Vec<_> = runs.iter().flat_map(|run| run.glyphs()).collect();
+            assert!(glyphs.iter().any(|glyph| glyph.glyph_id == tatweel));
+            // The glyphs still follow each other.
+            for pair in glyphs.windows(2) {
+                assert_close(pair[1].offset.x(), pair[0].offset.x() + pair[0].advance.x());
+            }
+        }
+    }
+}
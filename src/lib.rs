@@ -1,23 +1,167 @@
 #[macro_use]
 extern crate log;
 
+use std::ops::Range;
+
 use font_kit::loaders::default::Font;
+use font_kit::properties::Properties;
 use pathfinder_geometry::vector::Vector2F;
 
+mod bidi;
+mod bounds;
+mod cache;
 mod collection;
+mod decoration;
 mod hb_layout;
+mod hit_test;
+mod justify;
+#[cfg(feature = "line-break")]
+mod line_break;
+mod locale;
+mod matching;
+mod metrics;
+mod ot;
 mod session;
+#[allow(clippy::large_const_arrays)]
 mod tables;
 mod unicode_funcs;
 
+pub use crate::bounds::TextBounds;
+pub use crate::cache::{CacheStats, LayoutCache};
 pub use crate::collection::{FontCollection, FontFamily, FontRef};
+pub use crate::decoration::DecorationLine;
 pub use crate::hb_layout::layout_run;
-pub use crate::session::LayoutSession;
+#[cfg(feature = "line-break")]
+pub use crate::line_break::{Line, LineBreakStrategy, LineBreaks};
+pub use crate::locale::{Locale, LocaleList};
+pub use crate::matching::Synthesis;
+pub use crate::metrics::{DecorationMetrics, FontMetrics};
+pub use crate::session::{
+    ClusterInfo, ClusterIter, EllipsisPosition, GlyphInfo, LayoutRangeIter, LayoutRun,
+    LayoutSession, Placeholder, RunIter, StyleSpan, Truncation,
+};
 
 #[derive(Clone)]
 pub struct TextStyle {
     // This should be either horiz and vert, or a 2x2 matrix
     pub size: f32,
+    /// The base (paragraph) direction, used by the bidi algorithm.
+    pub direction: TextDirection,
+    pub writing_mode: WritingMode,
+    /// OpenType feature settings, applied in order after the default features.
+    pub features: Vec<FontFeature>,
+    /// Variation axis settings, for variable fonts.
+    pub variations: Vec<FontVariation>,
+    /// Set the `opsz` axis from `size`, unless it is set explicitly.
+    ///
+    /// The axis is set to `size` as it is, which is in pixels, while `opsz`
+    /// is defined in points. The two only agree at 72 pixels per inch; at
+    /// other resolutions, set `opsz` in `variations` instead.
+    pub auto_optical_size: bool,
+    /// The locales of the text, in priority order. Each script run is
+    /// localized by the first locale compatible with its script.
+    pub locales: LocaleList,
+    /// The requested weight, stretch and style, used to choose a font from
+    /// each family.
+    pub properties: Properties,
+    /// Extra space added to each cluster, in em. This is not applied to
+    /// scripts with connected letters, such as Arabic and Devanagari.
+    pub letter_spacing: f32,
+    /// Extra space added to word separators such as U+0020, in em.
+    pub word_spacing: f32,
+}
+
+/// An OpenType feature setting, such as `tnum` or `liga`.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct FontFeature {
+    pub tag: [u8; 4],
+    /// The feature value: 0 disables the feature, 1 enables it, and larger
+    /// values select alternates.
+    pub value: u32,
+    /// The byte range of the text the setting applies to, or `None` for all
+    /// of it.
+    pub range: Option<Range<usize>>,
+}
+
+/// The base direction of a paragraph.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TextDirection {
+    /// Determine the direction from the first strong character (rules P2 and P3
+    /// of UAX #9), defaulting to left-to-right.
+    Auto,
+    LeftToRight,
+    RightToLeft,
+}
+
+/// The direction in which lines progress.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WritingMode {
+    Horizontal,
+    /// Lines run top to bottom. Characters are set upright or sideways
+    /// according to the Unicode Vertical_Orientation property (UAX #50).
+    Vertical,
+}
+
+/// The orientation of the glyphs in a run.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RunOrientation {
+    /// Horizontal text.
+    Horizontal,
+    /// Vertical text, shaped top to bottom using the vertical metrics of the
+    /// font.
+    Upright,
+    /// Vertical text, shaped horizontally and turned sideways. Glyphs should
+    /// be drawn rotated 90 degrees clockwise about their origin.
+    Sideways,
+}
+
+/// A setting of a variation axis, such as `wght` or `wdth`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct FontVariation {
+    pub tag: [u8; 4],
+    pub value: f32,
+}
+
+impl TextStyle {
+    pub fn new(size: f32) -> TextStyle {
+        TextStyle {
+            size,
+            direction: TextDirection::Auto,
+            writing_mode: WritingMode::Horizontal,
+            features: Vec::new(),
+            variations: Vec::new(),
+            auto_optical_size: false,
+            locales: LocaleList::default(),
+            properties: Properties::new(),
+            letter_spacing: 0.0,
+            word_spacing: 0.0,
+        }
+    }
+}
+
+impl FontVariation {
+    pub fn new(tag: &[u8; 4], value: f32) -> FontVariation {
+        FontVariation { tag: *tag, value }
+    }
+}
+
+impl FontFeature {
+    pub fn new(tag: &[u8; 4], value: u32) -> FontFeature {
+        FontFeature {
+            tag: *tag,
+            value,
+            range: None,
+        }
+    }
+
+    /// A feature setting that applies only to the given range of the text.
+    pub fn with_range(tag: &[u8; 4], value: u32, range: Range<usize>) -> FontFeature {
+        FontFeature {
+            tag: *tag,
+            value,
+            range: Some(range),
+        }
+    }
 }
 
 // TODO: remove this (in favor of LayoutSession, which might take over this name)
@@ -0,0 +1,323 @@
+//! Breaking the text of a session into lines.
+//!
+//! Break opportunities come from the Unicode line breaking algorithm
+//! ([UAX #14]), and candidate lines are measured with
+//! `LayoutSession::advance_substr`, so shaping is mostly reused.
+//!
+//! [UAX #14]: https://www.unicode.org/reports/tr14/
+
+use std::ops::Range;
+
+use unicode_linebreak::{break_property, linebreaks, BreakClass, BreakOpportunity};
+
+use crate::session::{LayoutRangeIter, LayoutSession};
+
+/// How to choose among the possible breaks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LineBreakStrategy {
+    /// Fit as much as possible on each line, in order.
+    Greedy,
+    /// Choose the breaks that make the lines of each paragraph most even,
+    /// in the style of Knuth and Plass. The cost of a line is the square of
+    /// its unused width, and the last line of a paragraph is free.
+    Optimal,
+}
+
+/// A line of text.
+///
+/// As in a text editor, empty text has one empty line, and text ending with
+/// a line separator has an empty line after it.
+#[derive(Clone, Debug)]
+pub struct Line {
+    /// The range of the session text in the line, including any trailing
+    /// whitespace and the line separator itself.
+    pub range: Range<usize>,
+    /// The advance of the line along its direction, not counting trailing
+    /// whitespace.
+    pub width: f32,
+    /// Whether the line ends with a mandatory break, such as a newline. This
+    /// is always true for the last line, which ends at the end of the text.
+    pub mandatory_break: bool,
+}
+
+/// The result of breaking a session into lines.
+pub struct LineBreaks<'a, S: AsRef<str>> {
+    session: &'a mut LayoutSession<S>,
+    lines: Vec<Line>,
+}
+
+// A place where a line may end.
+struct Candidate {
+    offset: usize,
+    mandatory: bool,
+}
+
+impl<'a, S: AsRef<str>> LineBreaks<'a, S> {
+    /// Break the text of the session into lines no wider than `max_width`,
+    /// where possible. Words that don't fit on a line by themselves overflow.
+    pub fn new(
+        session: &'a mut LayoutSession<S>,
+        max_width: f32,
+        strategy: LineBreakStrategy,
+    ) -> LineBreaks<'a, S> {
+        let candidates: Vec<Candidate> = linebreaks(session.text())
+            .map(|(offset, opportunity)| Candidate {
+                offset,
+                mandatory: opportunity == BreakOpportunity::Mandatory,
+            })
+            .collect();
+        let ends = match strategy {
+            LineBreakStrategy::Greedy => break_greedy(session, &candidates, max_width),
+            LineBreakStrategy::Optimal => break_optimal(session, &candidates, max_width),
+        };
+        let mut lines = Vec::with_capacity(ends.len());
+        let mut start = 0;
+        for ix in ends {
+            let candidate = &candidates[ix];
+            lines.push(Line {
+                range: start..candidate.offset,
+                width: line_width(session, start, candidate.offset),
+                mandatory_break: candidate.mandatory,
+            });
+            start = candidate.offset;
+        }
+        let text = session.text();
+        if text.chars().next_back().is_none_or(is_line_separator) {
+            lines.push(Line {
+                range: text.len()..text.len(),
+                width: 0.0,
+                mandatory_break: true,
+            });
+        }
+        LineBreaks { session, lines }
+    }
+
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    /// Iterate through the runs of a line, as laid out by `iter_substr`.
+    pub fn iter_line(&mut self, line_ix: usize) -> LayoutRangeIter<'_> {
+        let range = self.lines[line_ix].range.clone();
+        self.session.iter_substr(range)
+    }
+}
+
+// The width of a line, with trailing whitespace hanging past the end.
+fn line_width<S: AsRef<str>>(session: &LayoutSession<S>, start: usize, end: usize) -> f32 {
+    let end = start + session.text()[start..end].trim_end().len();
+    if end == start {
+        return 0.0;
+    }
+    session
+        .line_position(session.advance_substr(start..end))
+        .abs()
+}
+
+fn is_line_separator(c: char) -> bool {
+    matches!(
+        break_property(c as u32),
+        BreakClass::Mandatory
+            | BreakClass::CarriageReturn
+            | BreakClass::LineFeed
+            | BreakClass::NextLine
+    )
+}
+
+// Returns the indices of the candidates that end lines.
+fn break_greedy<S: AsRef<str>>(
+    session: &LayoutSession<S>,
+    candidates: &[Candidate],
+    max_width: f32,
+) -> Vec<usize> {
+    let mut ends = Vec::new();
+    let mut start = 0;
+    // The last candidate that fits on the current line.
+    let mut fitting: Option<usize> = None;
+    let mut ix = 0;
+    while ix < candidates.len() {
+        let candidate = &candidates[ix];
+        if line_width(session, start, candidate.offset) > max_width {
+            if let Some(fitting_ix) = fitting {
+                // Break at the last fit, and try this candidate again.
+                ends.push(fitting_ix);
+                start = candidates[fitting_ix].offset;
+                fitting = None;
+                continue;
+            }
+            // Nothing fits, so this line overflows.
+            ends.push(ix);
+            start = candidate.offset;
+        } else if candidate.mandatory {
+            ends.push(ix);
+            start = candidate.offset;
+            fitting = None;
+        } else {
+            fitting = Some(ix);
+        }
+        ix += 1;
+    }
+    ends
+}
+
+fn break_optimal<S: AsRef<str>>(
+    session: &LayoutSession<S>,
+    candidates: &[Candidate],
+    max_width: f32,
+) -> Vec<usize> {
+    // Node 0 is the start of the text, and node i + 1 is candidate i. For
+    // each node, the lowest total cost of breaking there, and the previous
+    // node on that path.
+    let mut costs = vec![f32::INFINITY; candidates.len() + 1];
+    let mut previous = vec![0; candidates.len() + 1];
+    costs[0] = 0.0;
+    for from in 0..candidates.len() {
+        if costs[from].is_infinite() {
+            continue;
+        }
+        let start = if from == 0 {
+            0
+        } else {
+            candidates[from - 1].offset
+        };
+        for to in from..candidates.len() {
+            let candidate = &candidates[to];
+            let width = line_width(session, start, candidate.offset);
+            let overflow = width > max_width;
+            // An overflowing line is only allowed when nothing else fits.
+            if overflow && to > from {
+                break;
+            }
+            let line_cost = if overflow {
+                // Large enough to lose to any line that fits.
+                (width - max_width) * 1e6
+            } else if candidate.mandatory {
+                0.0
+            } else {
+                (max_width - width) * (max_width - width)
+            };
+            let cost = costs[from] + line_cost;
+            if cost < costs[to + 1] {
+                costs[to + 1] = cost;
+                previous[to + 1] = from;
+            }
+            if candidate.mandatory {
+                break;
+            }
+        }
+    }
+    let mut ends = Vec::new();
+    let mut node = candidates.len();
+    while node > 0 {
+        ends.push(node - 1);
+        node = previous[node];
+    }
+    ends.reverse();
+    ends
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+
+    use font_kit::family_name::FamilyName;
+    use font_kit::properties::Properties;
+    use font_kit::source::SystemSource;
+
+    use super::{LineBreakStrategy, LineBreaks};
+    use crate::{FontCollection, FontFamily, LayoutSession, TextStyle};
+
+    const STRATEGIES: [LineBreakStrategy; 2] =
+        [LineBreakStrategy::Greedy, LineBreakStrategy::Optimal];
+
+    fn collection() -> FontCollection {
+        let font = SystemSource::new()
+            .select_best_match(&[FamilyName::SansSerif], &Properties::new())
+            .unwrap()
+            .load()
+            .unwrap();
+        let mut collection = FontCollection::new();
+        collection.add_family(FontFamily::new_from_font(font));
+        collection
+    }
+
+    // The ranges and widths of the lines of the text, broken at `max_width`.
+    fn break_lines(
+        text: &str,
+        max_width: f32,
+        strategy: LineBreakStrategy,
+    ) -> Vec<(Range<usize>, f32)> {
+        let mut session = LayoutSession::create(text, &TextStyle::new(16.0), &collection());
+        let mut breaks = LineBreaks::new(&mut session, max_width, strategy);
+        let lines: Vec<_> = breaks
+            .lines()
+            .iter()
+            .map(|line| (line.range.clone(), line.width))
+            .collect();
+        for ix in 0..lines.len() {
+            breaks.iter_line(ix).count();
+        }
+        lines
+    }
+
+    fn ranges(text: &str, strategy: LineBreakStrategy) -> Vec<Range<usize>> {
+        break_lines(text, 1000.0, strategy)
+            .into_iter()
+            .map(|(range, _)| range)
+            .collect()
+    }
+
+    #[test]
+    fn blank_lines() {
+        for &strategy in &STRATEGIES {
+            let lines = break_lines("a\n\nb", 1000.0, strategy);
+            let ranges: Vec<_> = lines.iter().map(|(range, _)| range.clone()).collect();
+            assert_eq!(ranges, vec![0..2, 2..3, 3..4]);
+            assert_eq!(lines[1].1, 0.0);
+            assert!(lines[0].1 > 0.0);
+            let lines = break_lines("a\n   \nb", 1000.0, strategy);
+            assert_eq!(lines.len(), 3);
+            assert_eq!(lines[1].1, 0.0);
+        }
+    }
+
+    #[test]
+    fn edge_lines() {
+        for &strategy in &STRATEGIES {
+            assert_eq!(ranges("", strategy), vec![0..0]);
+            assert_eq!(ranges("abc", strategy), vec![0..3]);
+            assert_eq!(ranges("abc\n", strategy), vec![0..4, 4..4]);
+            assert_eq!(ranges("\n", strategy), vec![0..1, 1..1]);
+            assert_eq!(ranges("abc\r\n", strategy), vec![0..5, 5..5]);
+        }
+    }
+
+    #[test]
+    fn wraps_at_max_width() {
+        let words = "aaa bbb ccc";
+        let width = break_lines("aaa bbb", 1000.0, LineBreakStrategy::Greedy)[0].1;
+        for &strategy in &STRATEGIES {
+            let lines = break_lines(words, width + 0.1, strategy);
+            let ranges: Vec<_> = lines.iter().map(|(range, _)| range.clone()).collect();
+            assert_eq!(ranges, vec![0..8, 8..11]);
+            assert!(lines
+                .iter()
+                .all(|(_, line_width)| *line_width <= width + 0.1));
+        }
+    }
+
+    #[test]
+    fn line_end_whitespace_takes_paragraph_level() {
+        let text = "abc שלום עולם";
+        let width = break_lines("abc שלום", 1000.0, LineBreakStrategy::Greedy)[0].1;
+        let mut session = LayoutSession::create(text, &TextStyle::new(16.0), &collection());
+        let mut breaks = LineBreaks::new(&mut session, width + 0.1, LineBreakStrategy::Greedy);
+        assert_eq!(breaks.lines()[0].range, 0..13);
+        // The space after "שלום" is at the end of the line, on the right.
+        let runs: Vec<_> = breaks
+            .iter_line(0)
+            .map(|run| (run.text_range(), run.bidi_level()))
+            .collect();
+        assert_eq!(runs, vec![(0..4, 0), (4..12, 1), (12..13, 0)]);
+    }
+}
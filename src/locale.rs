@@ -0,0 +1,327 @@
+//! Locales and locale lists.
+
+use std::fmt;
+
+use harfbuzz::sys::{
+    hb_script_t, HB_SCRIPT_BOPOMOFO, HB_SCRIPT_COMMON, HB_SCRIPT_HAN, HB_SCRIPT_HANGUL,
+    HB_SCRIPT_HIRAGANA, HB_SCRIPT_INHERITED, HB_SCRIPT_KATAKANA, HB_SCRIPT_UNKNOWN,
+};
+
+/// A BCP-47 locale, such as `sr-Cyrl` or `zh-TW`.
+///
+/// Only the language, script and region subtags are interpreted. The script,
+/// if not given explicitly, is inferred from the language and region, in the
+/// spirit of the likely subtags algorithm.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Locale {
+    tag: String,
+    language: String,
+    region: Option<String>,
+    // Explicit or inferred script, as a 4-letter code ("Latn", "Hant", "Jpan").
+    script: Option<[u8; 4]>,
+}
+
+/// A prioritized list of locales.
+#[derive(Clone, PartialEq, Eq, Hash, Default, Debug)]
+pub struct LocaleList {
+    locales: Vec<Locale>,
+}
+
+impl Locale {
+    /// Parse a locale from a BCP-47 tag. Underscores are accepted as
+    /// separators, so POSIX-style names like `en_US` also work.
+    pub fn new(tag: &str) -> Locale {
+        let subtags: Vec<&str> = tag
+            .split(['-', '_'])
+            .filter(|subtag| !subtag.is_empty())
+            .collect();
+        let language = subtags
+            .first()
+            .map(|subtag| subtag.to_ascii_lowercase())
+            .unwrap_or_default();
+        let mut script = None;
+        let mut region = None;
+        for subtag in subtags.iter().skip(1) {
+            if subtag.len() == 4 && subtag.chars().all(|c| c.is_ascii_alphabetic()) {
+                if script.is_none() && region.is_none() {
+                    let mut code = [0; 4];
+                    for (i, c) in subtag.bytes().enumerate() {
+                        code[i] = if i == 0 {
+                            c.to_ascii_uppercase()
+                        } else {
+                            c.to_ascii_lowercase()
+                        };
+                    }
+                    script = Some(code);
+                }
+            } else if (subtag.len() == 2 && subtag.chars().all(|c| c.is_ascii_alphabetic()))
+                || (subtag.len() == 3 && subtag.chars().all(|c| c.is_ascii_digit()))
+            {
+                if region.is_none() {
+                    region = Some(subtag.to_ascii_uppercase());
+                }
+            } else {
+                // Variants and extensions end the part we interpret.
+                break;
+            }
+        }
+        let script = script.or_else(|| likely_script(&language, region.as_deref()));
+        Locale {
+            tag: subtags.join("-"),
+            language,
+            region,
+            script,
+        }
+    }
+
+    /// The tag, normalized to use `-` as the separator.
+    pub fn as_str(&self) -> &str {
+        &self.tag
+    }
+
+    /// The language subtag, in lowercase.
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+
+    /// The region subtag, in uppercase.
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    /// Whether text in the given script can be localized by this locale.
+    ///
+    /// Locales for which no script is known are considered to support all
+    /// scripts.
+    pub(crate) fn supports_script(&self, script: hb_script_t) -> bool {
+        let code = match self.script {
+            Some(code) => code,
+            None => return true,
+        };
+        match &code {
+            b"Jpan" => {
+                script == HB_SCRIPT_HAN
+                    || script == HB_SCRIPT_HIRAGANA
+                    || script == HB_SCRIPT_KATAKANA
+            }
+            b"Kore" => script == HB_SCRIPT_HAN || script == HB_SCRIPT_HANGUL,
+            b"Hans" => script == HB_SCRIPT_HAN,
+            b"Hant" => script == HB_SCRIPT_HAN || script == HB_SCRIPT_BOPOMOFO,
+            // hb_script_t values are the big-endian encoding of the script code.
+            _ => script == u32::from_be_bytes(code),
+        }
+    }
+
+    /// Whether this is a Chinese, Japanese or Korean locale, the ones that
+    /// determine the glyph forms of unified Han characters.
+    pub fn is_cjk(&self) -> bool {
+        self.han_variant().is_some()
+    }
+
+    /// Whether the two locales call for the same forms of Han characters.
+    pub(crate) fn matches_han_variant(&self, other: &Locale) -> bool {
+        self.han_variant().is_some() && self.han_variant() == other.han_variant()
+    }
+
+    fn han_variant(&self) -> Option<&[u8; 4]> {
+        match &self.script {
+            Some(code) if [b"Jpan", b"Kore", b"Hans", b"Hant"].contains(&code) => Some(code),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Locale({})", self.tag)
+    }
+}
+
+impl LocaleList {
+    pub fn new(locales: Vec<Locale>) -> LocaleList {
+        LocaleList { locales }
+    }
+
+    /// Create a locale list from BCP-47 tags, in priority order.
+    pub fn from_tags(tags: &[&str]) -> LocaleList {
+        LocaleList::new(tags.iter().map(|tag| Locale::new(tag)).collect())
+    }
+
+    pub fn locales(&self) -> &[Locale] {
+        &self.locales
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locales.is_empty()
+    }
+
+    /// The first CJK locale in the list, which controls Han unification.
+    pub fn first_cjk(&self) -> Option<&Locale> {
+        self.locales.iter().find(|locale| locale.is_cjk())
+    }
+
+    /// Choose the locale for a run of text in the given script: the first one
+    /// compatible with the script, or the first one overall for text without a
+    /// specific script.
+    pub(crate) fn locale_for_script(&self, script: hb_script_t) -> Option<&Locale> {
+        if script == HB_SCRIPT_COMMON
+            || script == HB_SCRIPT_INHERITED
+            || script == HB_SCRIPT_UNKNOWN
+        {
+            return self.locales.first();
+        }
+        self.locales
+            .iter()
+            .find(|locale| locale.supports_script(script))
+    }
+}
+
+// Infer the script of a locale that doesn't specify one explicitly.
+//
+// This covers the languages we have good reason to know about; it should
+// eventually be replaced by the full CLDR likely subtags data.
+fn likely_script(language: &str, region: Option<&str>) -> Option<[u8; 4]> {
+    let script = match language {
+        "zh" => match region {
+            Some("TW") | Some("HK") | Some("MO") => b"Hant",
+            _ => b"Hans",
+        },
+        "ja" => b"Jpan",
+        "ko" => b"Kore",
+        "ar" | "fa" | "ur" | "ps" | "sd" | "ug" | "ckb" => b"Arab",
+        "he" | "yi" => b"Hebr",
+        "ru" | "uk" | "be" | "bg" | "mk" | "sr" | "kk" | "ky" | "mn" | "tg" | "tt" | "ba"
+        | "cv" | "ce" | "os" => b"Cyrl",
+        "el" => b"Grek",
+        "hy" => b"Armn",
+        "ka" => b"Geor",
+        "hi" | "mr" | "ne" | "sa" | "kok" | "mai" | "bho" => b"Deva",
+        "bn" | "as" => b"Beng",
+        "pa" => b"Guru",
+        "gu" => b"Gujr",
+        "or" => b"Orya",
+        "ta" => b"Taml",
+        "te" => b"Telu",
+        "kn" => b"Knda",
+        "ml" => b"Mlym",
+        "si" => b"Sinh",
+        "th" => b"Thai",
+        "lo" => b"Laoo",
+        "km" => b"Khmr",
+        "my" => b"Mymr",
+        "bo" | "dz" => b"Tibt",
+        "dv" => b"Thaa",
+        "syr" => b"Syrc",
+        "am" | "ti" => b"Ethi",
+        "iu" => b"Cans",
+        "chr" => b"Cher",
+        "af" | "az" | "ca" | "cs" | "cy" | "da" | "de" | "en" | "eo" | "es" | "et" | "eu"
+        | "fi" | "fil" | "fr" | "ga" | "gl" | "ha" | "hr" | "hu" | "id" | "is" | "it" | "lt"
+        | "lv" | "ms" | "mt" | "nb" | "nl" | "nn" | "no" | "pl" | "pt" | "ro" | "sk" | "sl"
+        | "sq" | "sv" | "sw" | "tl" | "tr" | "uz" | "vi" | "yo" | "zu" => b"Latn",
+        _ => return None,
+    };
+    Some(*script)
+}
+
+#[cfg(test)]
+mod tests {
+    use harfbuzz::sys::{
+        HB_SCRIPT_ARABIC, HB_SCRIPT_COMMON, HB_SCRIPT_CYRILLIC, HB_SCRIPT_HAN, HB_SCRIPT_HANGUL,
+        HB_SCRIPT_HIRAGANA, HB_SCRIPT_LATIN,
+    };
+
+    use super::{Locale, LocaleList};
+
+    fn script(tag: &str) -> Option<[u8; 4]> {
+        Locale::new(tag).script
+    }
+
+    #[test]
+    fn parse_subtags() {
+        let locale = Locale::new("zh-Hant-TW");
+        assert_eq!(locale.as_str(), "zh-Hant-TW");
+        assert_eq!(locale.language(), "zh");
+        assert_eq!(locale.region(), Some("TW"));
+        assert_eq!(locale.script, Some(*b"Hant"));
+    }
+
+    #[test]
+    fn normalize_case_and_separators() {
+        let locale = Locale::new("EN_us");
+        assert_eq!(locale.as_str(), "EN-us");
+        assert_eq!(locale.language(), "en");
+        assert_eq!(locale.region(), Some("US"));
+        assert_eq!(script("sr-cYRL"), Some(*b"Cyrl"));
+    }
+
+    #[test]
+    fn numeric_region() {
+        assert_eq!(Locale::new("es-419").region(), Some("419"));
+    }
+
+    #[test]
+    fn variants_end_parsing() {
+        let locale = Locale::new("de-1996-CH");
+        assert_eq!(locale.region(), None);
+        let locale = Locale::new("en-US-x-twain");
+        assert_eq!(locale.region(), Some("US"));
+    }
+
+    #[test]
+    fn script_after_region_is_ignored() {
+        let locale = Locale::new("sr-RS-Latn");
+        assert_eq!(locale.region(), Some("RS"));
+        assert_eq!(locale.script, Some(*b"Cyrl"));
+    }
+
+    #[test]
+    fn infer_script() {
+        assert_eq!(script("zh"), Some(*b"Hans"));
+        assert_eq!(script("zh-CN"), Some(*b"Hans"));
+        assert_eq!(script("zh-HK"), Some(*b"Hant"));
+        assert_eq!(script("ja"), Some(*b"Jpan"));
+        assert_eq!(script("ar-EG"), Some(*b"Arab"));
+        assert_eq!(script("en"), Some(*b"Latn"));
+        assert_eq!(script("xx"), None);
+        assert_eq!(script(""), None);
+    }
+
+    #[test]
+    fn explicit_script_wins() {
+        assert_eq!(script("sr-Latn"), Some(*b"Latn"));
+        assert_eq!(script("zh-Hans-TW"), Some(*b"Hans"));
+    }
+
+    #[test]
+    fn supports_script() {
+        assert!(Locale::new("ja").supports_script(HB_SCRIPT_HIRAGANA));
+        assert!(Locale::new("ja").supports_script(HB_SCRIPT_HAN));
+        assert!(Locale::new("ko").supports_script(HB_SCRIPT_HANGUL));
+        assert!(!Locale::new("zh").supports_script(HB_SCRIPT_HANGUL));
+        assert!(Locale::new("ru").supports_script(HB_SCRIPT_CYRILLIC));
+        assert!(!Locale::new("ru").supports_script(HB_SCRIPT_LATIN));
+        assert!(Locale::new("xx").supports_script(HB_SCRIPT_LATIN));
+    }
+
+    #[test]
+    fn han_variants() {
+        assert!(Locale::new("zh-TW").matches_han_variant(&Locale::new("zh-Hant")));
+        assert!(!Locale::new("zh-TW").matches_han_variant(&Locale::new("zh-CN")));
+        assert!(!Locale::new("en").matches_han_variant(&Locale::new("en")));
+        assert!(Locale::new("ko-KR").is_cjk());
+        assert!(!Locale::new("vi").is_cjk());
+    }
+
+    #[test]
+    fn locale_for_script() {
+        let list = LocaleList::from_tags(&["en", "ar", "ja", "zh"]);
+        let tag = |script| list.locale_for_script(script).map(Locale::as_str);
+        assert_eq!(tag(HB_SCRIPT_LATIN), Some("en"));
+        assert_eq!(tag(HB_SCRIPT_ARABIC), Some("ar"));
+        assert_eq!(tag(HB_SCRIPT_HAN), Some("ja"));
+        assert_eq!(tag(HB_SCRIPT_COMMON), Some("en"));
+        assert_eq!(tag(HB_SCRIPT_HANGUL), None);
+        assert_eq!(list.first_cjk().map(Locale::as_str), Some("ja"));
+    }
+}
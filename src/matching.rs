@@ -0,0 +1,329 @@
+//! Font matching within a family, following the [CSS Fonts Level 4] algorithm.
+//!
+//! [CSS Fonts Level 4]: https://drafts.csswg.org/css-fonts-4/#font-style-matching
+
+use font_kit::properties::{Properties, Style, Weight};
+
+use crate::FontVariation;
+
+/// Faux bold and italic to apply when the chosen font is lighter or more
+/// upright than requested.
+///
+/// Advances in the layout already account for emboldening; renderers are
+/// responsible for transforming the outlines.
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct Synthesis {
+    /// The amount to thicken stems by, in the same units as the text size.
+    /// Outlines should be offset outward by half this amount on each side.
+    pub embolden: f32,
+    /// The angle to skew glyphs by, in degrees. Positive values slant to the
+    /// right, like italics.
+    pub skew: f32,
+}
+
+// The skew Skia applies for fake italics; tan(14°) is about 1/4.
+const SYNTHETIC_SKEW: f32 = 14.0;
+
+impl Synthesis {
+    /// Determine the synthesis needed when a font with properties `actual` is
+    /// used for text requesting `desired`.
+    ///
+    /// For variable fonts, `variations` is the instance used, with a value for
+    /// each axis. The `wght`, `ital` and `slnt` axes take precedence over the
+    /// properties the font reports, which are those of its default instance.
+    pub(crate) fn for_font(
+        desired: &Properties,
+        actual: &Properties,
+        variations: &[FontVariation],
+        size: f32,
+    ) -> Synthesis {
+        let mut actual = *actual;
+        for variation in variations {
+            match &variation.tag {
+                b"wght" => actual.weight = Weight(variation.value),
+                b"ital" if variation.value > 0.0 => actual.style = Style::Italic,
+                b"slnt" if variation.value != 0.0 => actual.style = Style::Oblique,
+                _ => {}
+            }
+        }
+        let mut synthesis = Synthesis::default();
+        if desired.weight.0 >= 600.0 && desired.weight.0 - actual.weight.0 >= 200.0 {
+            synthesis.embolden = embolden_strength(size);
+        }
+        if desired.style != Style::Normal && actual.style == Style::Normal {
+            synthesis.skew = SYNTHETIC_SKEW;
+        }
+        synthesis
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.embolden == 0.0 && self.skew == 0.0
+    }
+}
+
+// Follows Skia: 1/24 of the size for small text, down to 1/32 at 36px and
+// above.
+fn embolden_strength(size: f32) -> f32 {
+    let t = ((size - 9.0) / (36.0 - 9.0)).clamp(0.0, 1.0);
+    size * (1.0 / 24.0 + t * (1.0 / 32.0 - 1.0 / 24.0))
+}
+
+/// Find the candidate closest to the query, narrowing by stretch, then
+/// style, then weight. Ties go to the earliest candidate.
+pub(crate) fn find_best_match(candidates: &[Properties], query: &Properties) -> Option<usize> {
+    let mut matching: Vec<usize> = (0..candidates.len()).collect();
+    narrow(&mut matching, |ix| {
+        stretch_key(query.stretch.0, candidates[ix].stretch.0)
+    });
+    narrow(&mut matching, |ix| {
+        (style_key(query.style, candidates[ix].style), 0.0)
+    });
+    narrow(&mut matching, |ix| {
+        weight_key(query.weight.0, candidates[ix].weight.0)
+    });
+    matching.first().copied()
+}
+
+// Keep only the candidates with the best (lowest) key. Keys are a priority
+// tier and a distance within the tier.
+fn narrow(matching: &mut Vec<usize>, key: impl Fn(usize) -> (u32, f32)) {
+    let best = matching
+        .iter()
+        .map(|&ix| key(ix))
+        .min_by(|a, b| a.partial_cmp(b).unwrap());
+    if let Some(best) = best {
+        matching.retain(|&ix| key(ix) == best);
+    }
+}
+
+// Normal or condensed widths prefer narrower faces, expanded widths prefer
+// wider ones.
+fn stretch_key(desired: f32, value: f32) -> (u32, f32) {
+    if desired <= 1.0 {
+        if value <= desired {
+            (0, desired - value)
+        } else {
+            (1, value - desired)
+        }
+    } else if value >= desired {
+        (0, value - desired)
+    } else {
+        (1, desired - value)
+    }
+}
+
+fn style_key(desired: Style, value: Style) -> u32 {
+    let order = match desired {
+        Style::Normal => [Style::Normal, Style::Oblique, Style::Italic],
+        Style::Italic => [Style::Italic, Style::Oblique, Style::Normal],
+        Style::Oblique => [Style::Oblique, Style::Italic, Style::Normal],
+    };
+    order.iter().position(|&style| style == value).unwrap_or(3) as u32
+}
+
+// Weights between 400 and 500 look first at heavier weights up to 500, then
+// lighter weights, then heavier weights above 500. Otherwise lighter requests
+// prefer lighter faces and bolder requests prefer bolder faces.
+fn weight_key(desired: f32, value: f32) -> (u32, f32) {
+    if (400.0..=500.0).contains(&desired) {
+        if value >= desired && value <= 500.0 {
+            (0, value - desired)
+        } else if value < desired {
+            (1, desired - value)
+        } else {
+            (2, value - desired)
+        }
+    } else if desired < 400.0 {
+        if value <= desired {
+            (0, desired - value)
+        } else {
+            (1, value - desired)
+        }
+    } else if value >= desired {
+        (0, value - desired)
+    } else {
+        (1, desired - value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use font_kit::properties::{Properties, Stretch, Style, Weight};
+
+    use super::{find_best_match, Synthesis};
+    use crate::FontVariation;
+
+    fn props(weight: f32, stretch: f32, style: Style) -> Properties {
+        Properties {
+            weight: Weight(weight),
+            stretch: Stretch(stretch),
+            style,
+        }
+    }
+
+    fn weights(weights: &[f32]) -> Vec<Properties> {
+        weights
+            .iter()
+            .map(|&weight| props(weight, 1.0, Style::Normal))
+            .collect()
+    }
+
+    fn best_weight(candidates: &[f32], desired: f32) -> f32 {
+        let candidates = weights(candidates);
+        let query = props(desired, 1.0, Style::Normal);
+        candidates[find_best_match(&candidates, &query).unwrap()]
+            .weight
+            .0
+    }
+
+    #[test]
+    fn no_candidates() {
+        assert_eq!(find_best_match(&[], &Properties::new()), None);
+    }
+
+    #[test]
+    fn exact_match() {
+        let candidates = weights(&[300.0, 400.0, 700.0]);
+        assert_eq!(find_best_match(&candidates, &Properties::new()), Some(1));
+    }
+
+    #[test]
+    fn weight_400_prefers_500_then_lighter() {
+        assert_eq!(best_weight(&[300.0, 500.0, 600.0], 400.0), 500.0);
+        assert_eq!(best_weight(&[300.0, 600.0], 400.0), 300.0);
+        assert_eq!(best_weight(&[600.0, 700.0], 400.0), 600.0);
+    }
+
+    #[test]
+    fn weight_500_prefers_400_before_heavier() {
+        assert_eq!(best_weight(&[400.0, 600.0], 500.0), 400.0);
+    }
+
+    #[test]
+    fn light_weight_prefers_lighter() {
+        assert_eq!(best_weight(&[200.0, 350.0], 300.0), 200.0);
+        assert_eq!(best_weight(&[350.0, 500.0], 300.0), 350.0);
+    }
+
+    #[test]
+    fn bold_weight_prefers_heavier() {
+        assert_eq!(best_weight(&[500.0, 800.0], 600.0), 800.0);
+        assert_eq!(best_weight(&[400.0, 500.0], 600.0), 500.0);
+    }
+
+    #[test]
+    fn italic_falls_back_to_oblique_then_normal() {
+        let candidates = [
+            props(400.0, 1.0, Style::Normal),
+            props(400.0, 1.0, Style::Oblique),
+        ];
+        let query = props(400.0, 1.0, Style::Italic);
+        assert_eq!(find_best_match(&candidates, &query), Some(1));
+        let query = props(400.0, 1.0, Style::Normal);
+        assert_eq!(find_best_match(&candidates, &query), Some(0));
+        let query = props(400.0, 1.0, Style::Italic);
+        assert_eq!(find_best_match(&candidates[..1], &query), Some(0));
+    }
+
+    #[test]
+    fn stretch_narrows_before_style_and_weight() {
+        let candidates = [
+            props(400.0, 1.0, Style::Italic),
+            props(700.0, 0.75, Style::Normal),
+        ];
+        let query = props(700.0, 1.0, Style::Italic);
+        assert_eq!(find_best_match(&candidates, &query), Some(0));
+        let query = props(400.0, 0.75, Style::Italic);
+        assert_eq!(find_best_match(&candidates, &query), Some(1));
+    }
+
+    #[test]
+    fn condensed_prefers_narrower_and_expanded_prefers_wider() {
+        let candidates = [
+            props(400.0, 0.75, Style::Normal),
+            props(400.0, 1.25, Style::Normal),
+        ];
+        let query = props(400.0, 1.0, Style::Normal);
+        assert_eq!(find_best_match(&candidates, &query), Some(0));
+        let query = props(400.0, 1.125, Style::Normal);
+        assert_eq!(find_best_match(&candidates, &query), Some(1));
+    }
+
+    #[test]
+    fn ties_go_to_the_earliest() {
+        let candidates = weights(&[700.0, 700.0]);
+        let query = props(700.0, 1.0, Style::Normal);
+        assert_eq!(find_best_match(&candidates, &query), Some(0));
+    }
+
+    fn synthesis(desired: Properties, actual: Properties) -> Synthesis {
+        Synthesis::for_font(&desired, &actual, &[], 16.0)
+    }
+
+    #[test]
+    fn embolden_threshold() {
+        let regular = props(400.0, 1.0, Style::Normal);
+        assert!(synthesis(props(600.0, 1.0, Style::Normal), regular).embolden > 0.0);
+        assert!(synthesis(props(900.0, 1.0, Style::Normal), regular).embolden > 0.0);
+        // The requested weight must be at least 600.
+        let light = props(300.0, 1.0, Style::Normal);
+        assert_eq!(
+            synthesis(props(500.0, 1.0, Style::Normal), light).embolden,
+            0.0
+        );
+        // And at least 200 heavier than the font.
+        let medium = props(401.0, 1.0, Style::Normal);
+        assert_eq!(
+            synthesis(props(600.0, 1.0, Style::Normal), medium).embolden,
+            0.0
+        );
+        let bold = props(700.0, 1.0, Style::Normal);
+        assert!(synthesis(props(900.0, 1.0, Style::Normal), bold).embolden > 0.0);
+    }
+
+    #[test]
+    fn embolden_strength_scales_with_size() {
+        let desired = props(700.0, 1.0, Style::Normal);
+        let actual = props(400.0, 1.0, Style::Normal);
+        let embolden = |size| Synthesis::for_font(&desired, &actual, &[], size).embolden;
+        assert_eq!(embolden(9.0), 9.0 / 24.0);
+        assert_eq!(embolden(36.0), 36.0 / 32.0);
+        assert_eq!(embolden(72.0), 72.0 / 32.0);
+        assert!(embolden(20.0) > 20.0 / 32.0 && embolden(20.0) < 20.0 / 24.0);
+    }
+
+    #[test]
+    fn oblique_skew() {
+        let normal = props(400.0, 1.0, Style::Normal);
+        let italic = props(400.0, 1.0, Style::Italic);
+        let oblique = props(400.0, 1.0, Style::Oblique);
+        assert_eq!(synthesis(italic, normal).skew, 14.0);
+        assert_eq!(synthesis(oblique, normal).skew, 14.0);
+        assert_eq!(synthesis(italic, oblique).skew, 0.0);
+        assert_eq!(synthesis(oblique, italic).skew, 0.0);
+        assert_eq!(synthesis(normal, italic).skew, 0.0);
+        assert!(synthesis(normal, normal).is_none());
+    }
+
+    #[test]
+    fn variable_font_instance() {
+        let desired = props(700.0, 1.0, Style::Italic);
+        let actual = props(400.0, 1.0, Style::Normal);
+        let for_instance =
+            |variations: &[FontVariation]| Synthesis::for_font(&desired, &actual, variations, 16.0);
+        let bold = [FontVariation::new(b"wght", 700.0)];
+        assert_eq!(for_instance(&bold).embolden, 0.0);
+        assert_eq!(for_instance(&bold).skew, 14.0);
+        let light = [FontVariation::new(b"wght", 300.0)];
+        assert!(for_instance(&light).embolden > 0.0);
+        let italic = [FontVariation::new(b"ital", 1.0)];
+        assert_eq!(for_instance(&italic).skew, 0.0);
+        let slanted = [FontVariation::new(b"slnt", -12.0)];
+        assert_eq!(for_instance(&slanted).skew, 0.0);
+        let upright = [
+            FontVariation::new(b"slnt", 0.0),
+            FontVariation::new(b"ital", 0.0),
+        ];
+        assert_eq!(for_instance(&upright).skew, 14.0);
+    }
+}
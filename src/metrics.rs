@@ -0,0 +1,239 @@
+//! Font metrics, for line spacing and decorations.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::collection::FontId;
+use crate::hb_layout::limit_cache_size;
+use crate::ot::{hhea_table, os2_table, post_table, USE_TYPO_METRICS};
+use crate::FontRef;
+
+/// The vertical metrics of a font, scaled to the text size.
+///
+/// Values are read from the font's tables rather than from the platform, so
+/// they are the same everywhere. As for glyph offsets, y is up, so the
+/// descent is negative.
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct FontMetrics {
+    /// The ascent and descent come from the typographic values of the `OS/2`
+    /// table when the font sets `USE_TYPO_METRICS`, and otherwise from the
+    /// `hhea` table, as on most platforms.
+    pub ascent: f32,
+    pub descent: f32,
+    /// The recommended extra space between lines.
+    pub line_gap: f32,
+    /// The height of lowercase letters without ascenders, such as "x".
+    pub x_height: f32,
+    /// The height of capital letters with flat tops, such as "H".
+    pub cap_height: f32,
+}
+
+/// The placement of a decoration line, such as an underline, scaled to the
+/// text size.
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct DecorationMetrics {
+    /// The y coordinate of the top of the line, relative to the baseline.
+    /// This is negative for lines below the baseline.
+    pub position: f32,
+    pub thickness: f32,
+}
+
+impl FontMetrics {
+    /// The recommended distance between baselines.
+    pub fn line_height(&self) -> f32 {
+        self.ascent - self.descent + self.line_gap
+    }
+
+    // The larger of each value, with the descent extending further down.
+    pub(crate) fn max(self, other: FontMetrics) -> FontMetrics {
+        FontMetrics {
+            ascent: self.ascent.max(other.ascent),
+            descent: self.descent.min(other.descent),
+            line_gap: self.line_gap.max(other.line_gap),
+            x_height: self.x_height.max(other.x_height),
+            cap_height: self.cap_height.max(other.cap_height),
+        }
+    }
+
+    fn scale(self, scale: f32) -> FontMetrics {
+        FontMetrics {
+            ascent: self.ascent * scale,
+            descent: self.descent * scale,
+            line_gap: self.line_gap * scale,
+            x_height: self.x_height * scale,
+            cap_height: self.cap_height * scale,
+        }
+    }
+}
+
+impl DecorationMetrics {
+    fn scale(self, scale: f32) -> DecorationMetrics {
+        DecorationMetrics {
+            position: self.position * scale,
+            thickness: self.thickness * scale,
+        }
+    }
+}
+
+// The metrics of a font in font units, read from its tables once.
+#[derive(Clone, Copy)]
+struct UnscaledMetrics {
+    units_per_em: f32,
+    font: FontMetrics,
+    underline: DecorationMetrics,
+    strikeout: DecorationMetrics,
+}
+
+thread_local! {
+    static METRICS_CACHE: RefCell<HashMap<FontId, UnscaledMetrics>> =
+        RefCell::new(HashMap::new());
+}
+
+/// The metrics of a font at the given size.
+pub(crate) fn font_metrics(font: &FontRef, size: f32) -> FontMetrics {
+    let metrics = unscaled_metrics(font);
+    metrics.font.scale(size / metrics.units_per_em)
+}
+
+/// The underline of a font at the given size, from the `post` table.
+pub(crate) fn underline_metrics(font: &FontRef, size: f32) -> DecorationMetrics {
+    let metrics = unscaled_metrics(font);
+    metrics.underline.scale(size / metrics.units_per_em)
+}
+
+/// The strikeout of a font at the given size, from the `OS/2` table. Fonts
+/// without one get a line as thick as the underline, centered at half the
+/// x-height.
+pub(crate) fn strikeout_metrics(font: &FontRef, size: f32) -> DecorationMetrics {
+    let metrics = unscaled_metrics(font);
+    metrics.strikeout.scale(size / metrics.units_per_em)
+}
+
+fn unscaled_metrics(font: &FontRef) -> UnscaledMetrics {
+    METRICS_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let font_id = FontId::from_font(font);
+        limit_cache_size(&mut cache, &font_id);
+        *cache
+            .entry(font_id)
+            .or_insert_with(|| load_unscaled_metrics(font))
+    })
+}
+
+fn load_unscaled_metrics(font: &FontRef) -> UnscaledMetrics {
+    let fallback = font.font.metrics();
+    let os2 = os2_table(font);
+    let hhea = hhea_table(font).filter(|hhea| hhea.ascender != 0 || hhea.descender != 0);
+    let (ascent, descent, line_gap) = match (&os2, &hhea) {
+        (Some(os2), _) if os2.fs_selection & USE_TYPO_METRICS != 0 => (
+            os2.typo_ascender.into(),
+            os2.typo_descender.into(),
+            os2.typo_line_gap.into(),
+        ),
+        (_, Some(hhea)) => (
+            hhea.ascender.into(),
+            hhea.descender.into(),
+            hhea.line_gap.into(),
+        ),
+        (Some(os2), None) if os2.typo_ascender != 0 || os2.typo_descender != 0 => (
+            os2.typo_ascender.into(),
+            os2.typo_descender.into(),
+            os2.typo_line_gap.into(),
+        ),
+        (Some(os2), None) => (os2.win_ascent.into(), -f32::from(os2.win_descent), 0.0),
+        (None, None) => (fallback.ascent, fallback.descent, fallback.line_gap),
+    };
+    // Older fonts don't record the heights, so measure a glyph instead.
+    let glyph_height = |c| {
+        let glyph_id = font.font.glyph_for_char(c)?;
+        let bounds = font.font.typographic_bounds(glyph_id).ok()?;
+        Some(bounds.max_y())
+    };
+    let os2_height = |height: Option<i16>| height.filter(|&height| height > 0).map(f32::from);
+    let x_height = os2_height(os2.as_ref().and_then(|os2| os2.x_height))
+        .or_else(|| glyph_height('x'))
+        .unwrap_or(fallback.x_height);
+    let cap_height = os2_height(os2.as_ref().and_then(|os2| os2.cap_height))
+        .or_else(|| glyph_height('H'))
+        .unwrap_or(fallback.cap_height);
+    let underline = match post_table(font) {
+        Some(post) if post.underline_thickness > 0 => DecorationMetrics {
+            position: post.underline_position.into(),
+            thickness: post.underline_thickness.into(),
+        },
+        _ => DecorationMetrics {
+            position: fallback.underline_position,
+            thickness: fallback.underline_thickness,
+        },
+    };
+    let strikeout = match &os2 {
+        Some(os2) if os2.strikeout_size > 0 && os2.strikeout_position > 0 => DecorationMetrics {
+            position: os2.strikeout_position.into(),
+            thickness: os2.strikeout_size.into(),
+        },
+        _ => DecorationMetrics {
+            position: 0.5 * (x_height + underline.thickness),
+            thickness: underline.thickness,
+        },
+    };
+    UnscaledMetrics {
+        units_per_em: fallback.units_per_em as f32,
+        font: FontMetrics {
+            ascent,
+            descent,
+            line_gap,
+            x_height,
+            cap_height,
+        },
+        underline,
+        strikeout,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use font_kit::family_name::FamilyName;
+    use font_kit::properties::Properties;
+    use font_kit::source::SystemSource;
+
+    use super::{font_metrics, strikeout_metrics, underline_metrics};
+    use crate::FontRef;
+
+    fn load_font() -> FontRef {
+        let font = SystemSource::new()
+            .select_best_match(&[FamilyName::SansSerif], &Properties::new())
+            .unwrap()
+            .load()
+            .unwrap();
+        FontRef::new(font)
+    }
+
+    #[test]
+    fn metrics_scale_with_size() {
+        let font = load_font();
+        let small = font_metrics(&font, 10.0);
+        let large = font_metrics(&font, 20.0);
+        assert!(small.ascent > 0.0 && small.descent < 0.0);
+        assert!(small.x_height > 0.0 && small.cap_height > small.x_height);
+        assert_eq!(large.ascent, 2.0 * small.ascent);
+        assert_eq!(large.descent, 2.0 * small.descent);
+        assert_eq!(large.line_height(), 2.0 * small.line_height());
+        assert_eq!(
+            underline_metrics(&font, 20.0).thickness,
+            2.0 * underline_metrics(&font, 10.0).thickness
+        );
+        assert_eq!(
+            strikeout_metrics(&font, 20.0).position,
+            2.0 * strikeout_metrics(&font, 10.0).position
+        );
+    }
+
+    #[test]
+    fn metrics_are_the_same_for_a_reloaded_font() {
+        let metrics = font_metrics(&load_font(), 16.0);
+        assert_eq!(font_metrics(&load_font(), 16.0), metrics);
+        let strikeout = strikeout_metrics(&load_font(), 16.0);
+        assert!(strikeout.position > 0.0 && strikeout.thickness > 0.0);
+        assert!(strikeout.position < metrics.ascent);
+    }
+}
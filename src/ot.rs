@@ -0,0 +1,142 @@
+//! Parsing of OpenType tables that font-kit doesn't expose.
+
+use crate::FontRef;
+
+// Read big-endian values out of table data, returning None when out of bounds.
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_i16(data: &[u8], offset: usize) -> Option<i16> {
+    Some(read_u16(data, offset)? as i16)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_fixed(data: &[u8], offset: usize) -> Option<f32> {
+    Some(read_u32(data, offset)? as i32 as f32 * (1.0 / 65536.0))
+}
+
+/// A variation axis of a variable font, from the `fvar` table.
+#[derive(Clone, Debug)]
+pub(crate) struct VariationAxis {
+    pub(crate) tag: [u8; 4],
+    pub(crate) min_value: f32,
+    pub(crate) default_value: f32,
+    pub(crate) max_value: f32,
+}
+
+/// The variation axes of the font, empty if it is not a variable font.
+pub(crate) fn variation_axes(font: &FontRef) -> Vec<VariationAxis> {
+    font.font
+        .load_font_table(u32::from_be_bytes(*b"fvar"))
+        .and_then(|data| parse_fvar(&data))
+        .unwrap_or_default()
+}
+
+fn parse_fvar(data: &[u8]) -> Option<Vec<VariationAxis>> {
+    let axes_offset = read_u16(data, 4)? as usize;
+    let axis_count = read_u16(data, 8)? as usize;
+    let axis_size = read_u16(data, 10)? as usize;
+    let mut axes = Vec::with_capacity(axis_count);
+    for i in 0..axis_count {
+        let offset = axes_offset + i * axis_size;
+        axes.push(VariationAxis {
+            tag: read_u32(data, offset)?.to_be_bytes(),
+            min_value: read_fixed(data, offset + 4)?,
+            default_value: read_fixed(data, offset + 8)?,
+            max_value: read_fixed(data, offset + 12)?,
+        });
+    }
+    Some(axes)
+}
+
+/// Bit 7 of `fsSelection` in the `OS/2` table: the typographic ascender,
+/// descender and line gap should be used for line spacing.
+pub(crate) const USE_TYPO_METRICS: u16 = 1 << 7;
+
+/// Values from the `OS/2` table, in font units.
+#[derive(Clone, Debug)]
+pub(crate) struct Os2 {
+    pub(crate) strikeout_size: i16,
+    pub(crate) strikeout_position: i16,
+    pub(crate) fs_selection: u16,
+    pub(crate) typo_ascender: i16,
+    pub(crate) typo_descender: i16,
+    pub(crate) typo_line_gap: i16,
+    pub(crate) win_ascent: u16,
+    pub(crate) win_descent: u16,
+    /// Only present from version 2 of the table.
+    pub(crate) x_height: Option<i16>,
+    pub(crate) cap_height: Option<i16>,
+}
+
+/// Values from the `hhea` table, in font units.
+#[derive(Clone, Debug)]
+pub(crate) struct Hhea {
+    pub(crate) ascender: i16,
+    pub(crate) descender: i16,
+    pub(crate) line_gap: i16,
+}
+
+/// Values from the `post` table, in font units.
+#[derive(Clone, Debug)]
+pub(crate) struct Post {
+    pub(crate) underline_position: i16,
+    pub(crate) underline_thickness: i16,
+}
+
+pub(crate) fn os2_table(font: &FontRef) -> Option<Os2> {
+    let data = font.font.load_font_table(u32::from_be_bytes(*b"OS/2"))?;
+    parse_os2(&data)
+}
+
+pub(crate) fn hhea_table(font: &FontRef) -> Option<Hhea> {
+    let data = font.font.load_font_table(u32::from_be_bytes(*b"hhea"))?;
+    parse_hhea(&data)
+}
+
+pub(crate) fn post_table(font: &FontRef) -> Option<Post> {
+    let data = font.font.load_font_table(u32::from_be_bytes(*b"post"))?;
+    parse_post(&data)
+}
+
+fn parse_os2(data: &[u8]) -> Option<Os2> {
+    let version = read_u16(data, 0)?;
+    let (x_height, cap_height) = if version >= 2 {
+        (read_i16(data, 86), read_i16(data, 88))
+    } else {
+        (None, None)
+    };
+    Some(Os2 {
+        strikeout_size: read_i16(data, 26)?,
+        strikeout_position: read_i16(data, 28)?,
+        fs_selection: read_u16(data, 62)?,
+        typo_ascender: read_i16(data, 68)?,
+        typo_descender: read_i16(data, 70)?,
+        typo_line_gap: read_i16(data, 72)?,
+        win_ascent: read_u16(data, 74)?,
+        win_descent: read_u16(data, 76)?,
+        x_height,
+        cap_height,
+    })
+}
+
+fn parse_hhea(data: &[u8]) -> Option<Hhea> {
+    Some(Hhea {
+        ascender: read_i16(data, 4)?,
+        descender: read_i16(data, 6)?,
+        line_gap: read_i16(data, 8)?,
+    })
+}
+
+fn parse_post(data: &[u8]) -> Option<Post> {
+    Some(Post {
+        underline_position: read_i16(data, 8)?,
+        underline_thickness: read_i16(data, 10)?,
+    })
+}
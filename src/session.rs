@@ -1,37 +1,83 @@
 //! Retained layout that supports substring queries.
 
+use std::collections::BTreeSet;
+use std::mem;
 use std::ops::Range;
+use std::slice;
+use std::sync::Arc;
+
+use font_kit::loaders::default::Font;
 
 use harfbuzz::sys::{hb_script_t, HB_SCRIPT_COMMON, HB_SCRIPT_INHERITED, HB_SCRIPT_UNKNOWN};
 
-use pathfinder_geometry::vector::Vector2F;
+use pathfinder_geometry::rect::RectF;
+use pathfinder_geometry::vector::{vec2f, Vector2F};
+
+use unicode_vo::{char_orientation, Orientation};
 
+use crate::bidi::{bidi_levels, is_line_end_whitespace, paragraph_level, visual_order};
+use crate::bounds::{fragment_extents, fragments_bounds, logical_rect, TextBounds};
+use crate::cache::LayoutCache;
 use crate::hb_layout::layout_fragment;
+use crate::hit_test::{fragment_caret, fragment_carets, is_grapheme_boundary};
+use crate::justify::justify_fragments;
+use crate::metrics::{
+    font_metrics, strikeout_metrics, underline_metrics, DecorationMetrics, FontMetrics,
+};
 use crate::unicode_funcs::lookup_script;
-use crate::{FontCollection, FontRef, TextStyle};
+use crate::{
+    FontCollection, FontFeature, FontRef, FontVariation, Locale, RunOrientation, Synthesis,
+    TextStyle, WritingMode,
+};
 
 pub struct LayoutSession<S: AsRef<str>> {
     text: S,
-    style: TextStyle,
+    // The first style is the base style, which also sets the paragraph
+    // direction and writing mode.
+    styles: Vec<TextStyle>,
+    // Contiguous runs of text with the same style, covering the whole text.
+    style_runs: Vec<StyleSpan>,
+    // Fragments are stored in logical order.
     fragments: Vec<LayoutFragment>,
+    // Indices into `fragments`, in visual order.
+    visual_order: Vec<usize>,
 
     // A separate layout for the substring if needed.
     substr_fragments: Vec<LayoutFragment>,
+    substr_visual_order: Vec<usize>,
+
+    cache: Option<LayoutCache>,
 }
 
+#[derive(Clone)]
 pub(crate) struct LayoutFragment {
+    // Start of substring covered by this fragment, relative to the session text.
+    pub(crate) substr_start: usize,
     // Length of substring covered by this fragment.
     pub(crate) substr_len: usize,
     pub(crate) script: hb_script_t,
+    // The bidi embedding level; odd levels are right-to-left.
+    pub(crate) level: u8,
+    pub(crate) orientation: RunOrientation,
+    pub(crate) locale: Option<Locale>,
+    // The index of the style of the text.
+    pub(crate) style: usize,
     pub(crate) advance: Vector2F,
     pub(crate) glyphs: Vec<FragmentGlyph>,
     pub(crate) font: FontRef,
+    // The coordinates of the variable font instance, one per axis.
+    pub(crate) variations: Vec<FontVariation>,
+    pub(crate) synthesis: Synthesis,
+    // The inline object laid out by this fragment, if any. It has a single
+    // glyph, standing in for the object, so that it behaves like a cluster.
+    pub(crate) placeholder: Option<Placeholder>,
 }
 
 // This should probably be renamed "glyph".
 //
 // Discussion topic: this is so similar to hb_glyph_info_t, maybe we
 // should just use that.
+#[derive(Clone)]
 pub(crate) struct FragmentGlyph {
     pub cluster: u32,
     pub glyph_id: u32,
@@ -40,16 +86,78 @@ pub(crate) struct FragmentGlyph {
     pub unsafe_to_break: bool,
 }
 
+// A maximal run of text with uniform bidi level, script, orientation and
+// style.
+pub(crate) struct Segment {
+    pub(crate) range: Range<usize>,
+    pub(crate) level: u8,
+    pub(crate) script: hb_script_t,
+    pub(crate) orientation: RunOrientation,
+    // The locale chosen for the script from the style's locale list.
+    pub(crate) locale: Option<Locale>,
+    pub(crate) style: usize,
+    pub(crate) placeholder: Option<Placeholder>,
+}
+
+/// A range of the text to lay out with one of the styles of a session, or
+/// as an inline object.
+#[derive(Clone, PartialEq, Debug)]
+pub struct StyleSpan {
+    pub range: Range<usize>,
+    /// The index of the style in the list passed to
+    /// `LayoutSession::create_styled`.
+    pub style: usize,
+    /// Lay out the range as an inline object instead of shaping it.
+    pub placeholder: Option<Placeholder>,
+}
+
+/// An inline object, such as an image or a widget, laid out in place of the
+/// text of a span.
+///
+/// The object is a single cluster, which can't be broken, with the given
+/// advance. Its text is typically U+FFFC OBJECT REPLACEMENT CHARACTER.
+/// Dimensions are in the same units as the text size.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Placeholder {
+    /// The advance along the line.
+    pub width: f32,
+    /// The extent above the baseline.
+    pub ascent: f32,
+    /// The extent below the baseline, which is negative as for font metrics.
+    pub descent: f32,
+}
+
+/// Where to elide text that doesn't fit.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EllipsisPosition {
+    Start,
+    Middle,
+    End,
+}
+
+/// The layout of text truncated to fit a width.
+pub struct Truncation<'a> {
+    /// The range of the session text replaced by the ellipsis, or None if
+    /// the text fits without truncation.
+    pub elided: Option<Range<usize>>,
+    pub runs: LayoutRangeIter<'a>,
+}
+
+const ELLIPSIS: &str = "\u{2026}";
+
 pub struct LayoutRangeIter<'a> {
+    styles: &'a [TextStyle],
     fragments: &'a [LayoutFragment],
+    visual_order: &'a [usize],
     offset: Vector2F,
-    fragment_ix: usize,
+    ix: usize,
 }
 
 pub struct LayoutRun<'a> {
     // This should potentially be in fragment (would make it easier to binary search)
     offset: Vector2F,
     fragment: &'a LayoutFragment,
+    style: &'a TextStyle,
 }
 
 pub struct RunIter<'a> {
@@ -61,32 +169,123 @@ pub struct RunIter<'a> {
 pub struct GlyphInfo {
     pub glyph_id: u32,
     pub offset: Vector2F,
+    pub advance: Vector2F,
+    /// The range of the session text in the cluster this glyph belongs to.
+    pub cluster: Range<usize>,
+    /// Breaking the text at the start of the cluster would change the shaping
+    /// of the glyphs around it, so a break there requires reshaping.
+    pub unsafe_to_break: bool,
+}
+
+pub struct ClusterIter<'a> {
+    offset: Vector2F,
+    fragment: &'a LayoutFragment,
+    glyph_ix: usize,
+}
+
+/// A cluster: the smallest unit of text that maps to a sequence of glyphs.
+pub struct ClusterInfo {
+    /// The range of the session text in the cluster.
+    pub text_range: Range<usize>,
+    /// The indices of the cluster's glyphs within the run. This is empty
+    /// for an inline object.
+    pub glyph_range: Range<usize>,
+    /// The position of the cluster's visually first glyph.
+    pub offset: Vector2F,
+    pub advance: Vector2F,
+}
+
+impl StyleSpan {
+    pub fn new(range: Range<usize>, style: usize) -> StyleSpan {
+        StyleSpan {
+            range,
+            style,
+            placeholder: None,
+        }
+    }
 }
 
 impl<S: AsRef<str>> LayoutSession<S> {
-    pub fn create(
+    pub fn create(text: S, style: &TextStyle, collection: &FontCollection) -> LayoutSession<S> {
+        LayoutSession::create_impl(text, slice::from_ref(style), &[], collection, None)
+    }
+
+    /// Create a session, reusing shaping results from the cache when
+    /// possible. Substring layouts requested from the session also use the
+    /// cache.
+    pub fn create_with_cache(
         text: S,
         style: &TextStyle,
         collection: &FontCollection,
+        cache: &LayoutCache,
+    ) -> LayoutSession<S> {
+        let cache = Some(cache.clone());
+        LayoutSession::create_impl(text, slice::from_ref(style), &[], collection, cache)
+    }
+
+    /// Create a session for text with several styles, such as a sentence
+    /// with a bold word.
+    ///
+    /// Each span applies a style to a range of the text; later spans take
+    /// precedence where they overlap, and text not covered by any span uses
+    /// the first style. The paragraph direction and writing mode always come
+    /// from the first style. Text is itemized by style as well as by script
+    /// and font, and each run reports the index of its style. Spans with a
+    /// placeholder are laid out as inline objects, in runs of their own.
+    ///
+    /// Panics if there are no styles, or if a span refers to a style that
+    /// doesn't exist.
+    pub fn create_styled(
+        text: S,
+        styles: &[TextStyle],
+        spans: &[StyleSpan],
+        collection: &FontCollection,
+    ) -> LayoutSession<S> {
+        LayoutSession::create_impl(text, styles, spans, collection, None)
+    }
+
+    /// Create a session for text with several styles, reusing shaping
+    /// results from the cache when possible.
+    pub fn create_styled_with_cache(
+        text: S,
+        styles: &[TextStyle],
+        spans: &[StyleSpan],
+        collection: &FontCollection,
+        cache: &LayoutCache,
     ) -> LayoutSession<S> {
-        let mut i = 0;
+        LayoutSession::create_impl(text, styles, spans, collection, Some(cache.clone()))
+    }
+
+    fn create_impl(
+        text: S,
+        styles: &[TextStyle],
+        spans: &[StyleSpan],
+        collection: &FontCollection,
+        cache: Option<LayoutCache>,
+    ) -> LayoutSession<S> {
+        assert!(!styles.is_empty(), "a session needs at least one style");
+        let style_runs = style_runs(text.as_ref().len(), styles.len(), spans);
         let mut fragments = Vec::new();
-        while i < text.as_ref().len() {
-            let (script, script_len) = get_script_run(&text.as_ref()[i..]);
-            let script_substr = &text.as_ref()[i..i + script_len];
-            for (range, font) in collection.itemize(script_substr) {
-                let fragment = layout_fragment(style, font, script, &script_substr[range]);
+        for segment in segment_text(text.as_ref(), styles, &style_runs) {
+            let segment_start = segment.range.start;
+            let segment_substr = &text.as_ref()[segment.range.clone()];
+            let style = &styles[segment.style];
+            for (range, font) in segment_items(collection, segment_substr, &segment, style) {
+                let range = segment_start + range.start..segment_start + range.end;
+                let fragment = shape(cache.as_ref(), style, font, &segment, text.as_ref(), range);
                 fragments.push(fragment);
             }
-            i += script_len;
         }
-        let substr_fragments = Vec::new();
+        let visual_order = fragments_visual_order(&fragments);
         LayoutSession {
             text,
-            // Does this clone mean we should take style arg by-move?
-            style: style.clone(),
+            styles: styles.to_vec(),
+            style_runs,
             fragments,
-            substr_fragments,
+            visual_order,
+            substr_fragments: Vec::new(),
+            substr_visual_order: Vec::new(),
+            cache,
         }
     }
 
@@ -95,77 +294,1095 @@ impl<S: AsRef<str>> LayoutSession<S> {
         self.text.as_ref()
     }
 
-    /// Returns a reference to the style passed to LayoutSession::create().
+    /// Returns a reference to the style passed to LayoutSession::create(),
+    /// or the first style of a session with several styles.
     pub fn style(&self) -> &TextStyle {
-        &self.style
+        &self.styles[0]
+    }
+
+    /// Returns the styles passed to LayoutSession::create_styled().
+    pub fn styles(&self) -> &[TextStyle] {
+        &self.styles
     }
 
     /// Iterate through all glyphs in the layout.
     ///
+    /// Runs are yielded in visual order, left to right.
+    ///
     /// Note: this is redundant with `iter_substr` with the whole string, might
     /// not keep it.
-    pub fn iter_all(&self) -> LayoutRangeIter {
+    pub fn iter_all(&self) -> LayoutRangeIter<'_> {
         LayoutRangeIter {
+            styles: &self.styles,
             offset: Vector2F::zero(),
             fragments: &self.fragments,
-            fragment_ix: 0,
+            visual_order: &self.visual_order,
+            ix: 0,
         }
     }
 
     /// Iterate through the glyphs in the layout of the substring.
     ///
     /// This method reuses as much of the original layout as practical, almost
-    /// entirely reusing the itemization, but possibly doing re-layout.
-    pub fn iter_substr(&mut self, range: Range<usize>) -> LayoutRangeIter {
+    /// entirely reusing the itemization, but possibly doing re-layout. The
+    /// substring is treated as a line for the purpose of bidi reordering:
+    /// levels are resolved for the whole paragraph, and whitespace at the end
+    /// of the substring is then reset to the paragraph level, so that it goes
+    /// at the visual end of the line.
+    pub fn iter_substr(&mut self, range: Range<usize>) -> LayoutRangeIter<'_> {
         if range == (0..self.text.as_ref().len()) {
             return self.iter_all();
         }
-        // TODO: reuse existing layout if unsafe_to_break flag is false at both endpoints.
-        let mut fragment_ix = 0;
-        let mut str_offset = 0;
-        while fragment_ix < self.fragments.len() {
-            let fragment_len = self.fragments[fragment_ix].substr_len;
-            if str_offset + fragment_len > range.start {
-                break;
+        self.layout_substr(range);
+        self.iter_substr_fragments()
+    }
+
+    /// Iterate through the glyphs in the layout of the substring, justified
+    /// to the given width.
+    ///
+    /// Extra space goes at word separators, between the characters of CJK
+    /// text, and within Arabic words as kashida (tatweel glyphs, one place
+    /// per word). Text with none of these is expanded between all characters,
+    /// except in scripts with connected letters. Glyphs are only moved, not reshaped.
+    /// Words are not lengthened with the justification axis of a variable
+    /// font, if it has one.
+    /// Trailing whitespace hangs: it is not expanded and doesn't count towards
+    /// `width`, so the runs end past `width` by its advance. A substring that
+    /// is already at least `width` long is laid out as by `iter_substr`.
+    pub fn iter_justified(&mut self, range: Range<usize>, width: f32) -> LayoutRangeIter<'_> {
+        self.layout_substr(range);
+        justify_fragments(
+            &mut self.substr_fragments,
+            self.text.as_ref(),
+            &self.styles,
+            width,
+        );
+        self.iter_substr_fragments()
+    }
+
+    /// Lay out the text to fit in `width`, replacing part of it with an
+    /// ellipsis if it doesn't.
+    ///
+    /// The ellipsis is shaped with the font of the run next to it, or with a
+    /// font from the collection if that font doesn't have one. The elided
+    /// text starts and ends on grapheme boundaries, and whitespace next to
+    /// the ellipsis is elided too. The ellipsis run has an empty text range
+    /// at the start of the elided text.
+    pub fn iter_truncated(
+        &mut self,
+        width: f32,
+        position: EllipsisPosition,
+        collection: &FontCollection,
+    ) -> Truncation<'_> {
+        let len = self.text().len();
+        if self.fragments.is_empty() || self.measure(0..len) <= width {
+            return Truncation {
+                elided: None,
+                runs: self.iter_all(),
+            };
+        }
+        let elided = self.elided_range(width, position, collection);
+        let adjacent = match position {
+            EllipsisPosition::Start => self.fragment_after(elided.end),
+            _ => self.fragment_before(elided.start),
+        };
+        let ellipsis = self.ellipsis_fragment(adjacent, elided.start, collection);
+        // Take the vector so it can be filled while borrowing self.
+        let mut substr_fragments = mem::take(&mut self.substr_fragments);
+        substr_fragments.clear();
+        self.push_substr_fragments(0..elided.start, &mut substr_fragments);
+        substr_fragments.push(ellipsis);
+        self.push_substr_fragments(elided.end..len, &mut substr_fragments);
+        self.substr_fragments = substr_fragments;
+        self.substr_visual_order = fragments_visual_order(&self.substr_fragments);
+        Truncation {
+            elided: Some(elided),
+            runs: self.iter_substr_fragments(),
+        }
+    }
+
+    // Choose the range to replace with an ellipsis, so the rest fits.
+    fn elided_range(
+        &self,
+        width: f32,
+        position: EllipsisPosition,
+        collection: &FontCollection,
+    ) -> Range<usize> {
+        let text = self.text();
+        let len = text.len();
+        let boundaries: Vec<usize> = (0..=len)
+            .filter(|&offset| is_grapheme_boundary(text, offset))
+            .collect();
+        let ellipsis_width = |fragment| {
+            let advance = self.ellipsis_fragment(fragment, 0, collection).advance;
+            self.line_position(advance).abs()
+        };
+        // The last boundary at which the text before it, and an ellipsis,
+        // fit in the given width.
+        let head_end = |width: f32| {
+            let fitting = boundaries.partition_point(|&offset| {
+                let ellipsis = ellipsis_width(self.fragment_before(offset));
+                self.measure(0..offset) + ellipsis <= width
+            });
+            boundaries[fitting.max(1) - 1]
+        };
+        // The first boundary, not before `min`, at which the text after it
+        // fits.
+        let tail_start = |width: f32, min: usize| {
+            let ix = boundaries
+                .partition_point(|&offset| offset < min || self.measure(offset..len) > width);
+            boundaries.get(ix).copied().unwrap_or(len)
+        };
+        let (start, end) = match position {
+            EllipsisPosition::End => (head_end(width), len),
+            EllipsisPosition::Start => {
+                let ellipsis = ellipsis_width(self.fragment_after(len));
+                (0, tail_start(width - ellipsis, 0))
+            }
+            EllipsisPosition::Middle => {
+                let start = head_end(0.5 * width);
+                let ellipsis = ellipsis_width(self.fragment_before(start));
+                let remaining = width - ellipsis - self.measure(0..start);
+                (start, tail_start(remaining, start))
+            }
+        };
+        // Don't leave whitespace next to the ellipsis.
+        let start = text[..start].trim_end().len();
+        let end = len - text[end..].trim_start().len();
+        start..end.max(start)
+    }
+
+    // The fragment containing the text just before the offset, or the first
+    // fragment.
+    fn fragment_before(&self, offset: usize) -> &LayoutFragment {
+        let ix = self
+            .fragments
+            .partition_point(|fragment| fragment.substr_start + fragment.substr_len < offset);
+        &self.fragments[ix.min(self.fragments.len() - 1)]
+    }
+
+    // The fragment containing the text just after the offset, or the last
+    // fragment.
+    fn fragment_after(&self, offset: usize) -> &LayoutFragment {
+        let ix = self
+            .fragments
+            .partition_point(|fragment| fragment.substr_start + fragment.substr_len <= offset);
+        &self.fragments[ix.min(self.fragments.len() - 1)]
+    }
+
+    // Shape an ellipsis like the adjacent fragment, placed at the offset.
+    fn ellipsis_fragment(
+        &self,
+        adjacent: &LayoutFragment,
+        offset: usize,
+        collection: &FontCollection,
+    ) -> LayoutFragment {
+        let adjacent_style = &self.styles[adjacent.style];
+        let mut font = &adjacent.font;
+        let mut text = ELLIPSIS;
+        if font.font.glyph_for_char('\u{2026}').is_none() {
+            let fallback = collection
+                .itemize_with_style(ELLIPSIS, adjacent_style)
+                .map(|(_, font)| font)
+                .find(|font| font.font.glyph_for_char('\u{2026}').is_some());
+            match fallback {
+                Some(fallback) => font = fallback,
+                None => text = "...",
             }
-            str_offset += fragment_len;
-            fragment_ix += 1;
         }
-        self.substr_fragments.clear();
-        while str_offset < range.end {
-            let fragment = &self.fragments[fragment_ix];
-            let fragment_len = fragment.substr_len;
-            let substr_start = range.start.max(str_offset);
-            let substr_end = range.end.min(str_offset + fragment_len);
-            let substr = &self.text.as_ref()[substr_start..substr_end];
-            let font = &fragment.font;
-            let script = fragment.script;
-            // TODO: we should pass in the hb_face too, just for performance.
-            let substr_fragment = layout_fragment(&self.style, font, script, substr);
-            self.substr_fragments.push(substr_fragment);
-            str_offset += fragment_len;
-            fragment_ix += 1;
+        // Features for ranges of the session text don't apply.
+        let style = TextStyle {
+            features: adjacent_style
+                .features
+                .iter()
+                .filter(|feature| feature.range.is_none())
+                .cloned()
+                .collect(),
+            ..adjacent_style.clone()
+        };
+        // The ellipsis goes at the start or end of the line, so it takes the
+        // paragraph level rather than the level of the adjacent text.
+        let level = self.fragments.iter().map(|fragment| fragment.level).min();
+        let segment = Segment {
+            range: 0..text.len(),
+            level: level.unwrap_or(adjacent.level),
+            placeholder: None,
+            ..adjacent.segment()
+        };
+        let mut fragment = shape(
+            self.cache.as_ref(),
+            &style,
+            font,
+            &segment,
+            text,
+            0..text.len(),
+        );
+        for glyph in &mut fragment.glyphs {
+            glyph.cluster = 0;
+        }
+        fragment.substr_start = offset;
+        fragment.substr_len = 0;
+        fragment
+    }
+
+    // The length of the layout of the substring along the line.
+    fn measure(&self, range: Range<usize>) -> f32 {
+        self.line_position(self.advance_substr(range)).abs()
+    }
+
+    // Fill `substr_fragments` with the layout of the substring.
+    fn layout_substr(&mut self, range: Range<usize>) {
+        // Take the vector so it can be filled while borrowing self.
+        let mut substr_fragments = mem::take(&mut self.substr_fragments);
+        substr_fragments.clear();
+        self.push_line_fragments(range, &mut substr_fragments);
+        self.substr_fragments = substr_fragments;
+        self.substr_visual_order = fragments_visual_order(&self.substr_fragments);
+    }
+
+    // Lay out the substring as a line. Whitespace at the end of the line
+    // takes the paragraph level (rule L1 of UAX #9), as it does at the end
+    // of a paragraph, so that it goes at the end of the line visually.
+    fn push_line_fragments(&self, range: Range<usize>, fragments: &mut Vec<LayoutFragment>) {
+        let text = self.text();
+        let trailing = text[range.clone()].trim_end_matches(is_line_end_whitespace);
+        let trailing_start = range.start + trailing.len();
+        self.push_substr_fragments(range.start..trailing_start, fragments);
+        if trailing_start == range.end {
+            return;
+        }
+        let para_start = paragraph_start(text, trailing_start);
+        let para_level = paragraph_level(&text[para_start..], self.style().direction);
+        for fragment in self.overlapping_fragments(trailing_start..range.end) {
+            let substr_range = fragment.clamp_range(trailing_start..range.end);
+            if substr_range.is_empty() {
+                continue;
+            }
+            if fragment.level == para_level || fragment.placeholder.is_some() {
+                fragments.push(self.substr_fragment(fragment, substr_range));
+            } else {
+                let segment = Segment {
+                    level: para_level,
+                    ..fragment.segment()
+                };
+                fragments.push(shape(
+                    self.cache.as_ref(),
+                    &self.styles[fragment.style],
+                    &fragment.font,
+                    &segment,
+                    text,
+                    substr_range,
+                ));
+            }
         }
+    }
+
+    fn push_substr_fragments(&self, range: Range<usize>, fragments: &mut Vec<LayoutFragment>) {
+        for fragment in self.overlapping_fragments(range.clone()) {
+            let substr_range = fragment.clamp_range(range.clone());
+            // There are no glyphs for empty text.
+            if substr_range.is_empty() {
+                continue;
+            }
+            fragments.push(self.substr_fragment(fragment, substr_range));
+        }
+    }
+
+    fn iter_substr_fragments(&self) -> LayoutRangeIter<'_> {
         LayoutRangeIter {
+            styles: &self.styles,
             offset: Vector2F::zero(),
             fragments: &self.substr_fragments,
-            fragment_ix: 0,
+            visual_order: &self.substr_visual_order,
+            ix: 0,
+        }
+    }
+
+    /// The advance of the layout of the substring.
+    ///
+    /// This is the same as the total advance of the runs from `iter_substr`,
+    /// but doesn't allocate when both ends of the range are safe to break.
+    /// Otherwise, only the text near the ends is reshaped.
+    pub fn advance_substr(&self, range: Range<usize>) -> Vector2F {
+        if range.is_empty() {
+            return Vector2F::zero();
+        }
+        let mut advance = Vector2F::zero();
+        for fragment in self.overlapping_fragments(range.clone()) {
+            let substr_range = fragment.clamp_range(range.clone());
+            if !substr_range.is_empty() {
+                advance += self.substr_advance(fragment, substr_range);
+            }
+        }
+        advance
+    }
+
+    /// The logical and ink bounds of the whole layout.
+    pub fn bounds(&self) -> TextBounds {
+        fragments_bounds(&self.fragments, &self.visual_order, &self.styles)
+    }
+
+    /// The logical and ink bounds of the layout of the substring, as given by
+    /// `iter_substr`.
+    pub fn bounds_substr(&self, range: Range<usize>) -> TextBounds {
+        if range == (0..self.text.as_ref().len()) {
+            return self.bounds();
+        }
+        // Empty ranges have no fragments, and get empty bounds.
+        let mut fragments = Vec::new();
+        self.push_line_fragments(range, &mut fragments);
+        let visual_order = fragments_visual_order(&fragments);
+        fragments_bounds(&fragments, &visual_order, &self.styles)
+    }
+
+    /// The metrics of the fonts used in the layout, including fallback
+    /// fonts, taking the largest of each value. Fonts are scaled to the size
+    /// of the text they are used for. Inline objects are not included.
+    pub fn metrics(&self) -> FontMetrics {
+        // Each font and size is only measured once.
+        let mut seen: Vec<(&Arc<Font>, f32)> = Vec::new();
+        let mut metrics: Option<FontMetrics> = None;
+        for fragment in &self.fragments {
+            let key = (&fragment.font.font, self.styles[fragment.style].size);
+            let is_seen = seen
+                .iter()
+                .any(|&(font, size)| Arc::ptr_eq(font, key.0) && size == key.1);
+            if fragment.placeholder.is_some() || is_seen {
+                continue;
+            }
+            seen.push(key);
+            let size = key.1;
+            let fragment_metrics = font_metrics(&fragment.font, size);
+            metrics = Some(metrics.map_or(fragment_metrics, |m| m.max(fragment_metrics)));
+        }
+        metrics.unwrap_or_default()
+    }
+
+    /// The grapheme boundary with the caret closest to the given position.
+    ///
+    /// The position is measured along the line from the start of the layout:
+    /// it is an x coordinate, or a y coordinate in vertical text (which is
+    /// negative, since y is up).
+    pub fn offset_for_advance(&self, advance: f32) -> usize {
+        let mut best_offset = 0;
+        let mut best_distance = f32::INFINITY;
+        let mut pen = Vector2F::zero();
+        for &ix in &self.visual_order {
+            let fragment = &self.fragments[ix];
+            fragment_carets(fragment, self.text(), |offset, position| {
+                let distance = (self.line_position(pen + position) - advance).abs();
+                if distance < best_distance {
+                    best_offset = offset;
+                    best_distance = distance;
+                }
+            });
+            pen += fragment.advance;
+        }
+        best_offset
+    }
+
+    /// The caret position for a byte offset, measured along the line in the
+    /// same way as for `offset_for_advance`.
+    ///
+    /// Offsets within a grapheme are moved back to its start. At the boundary
+    /// between two runs, the caret is placed at the edge of the run that
+    /// contains the text after the offset.
+    pub fn advance_for_offset(&self, offset: usize) -> f32 {
+        if self.fragments.is_empty() {
+            return 0.0;
+        }
+        let fragment_ix = self
+            .fragments
+            .partition_point(|fragment| fragment.substr_start + fragment.substr_len <= offset)
+            .min(self.fragments.len() - 1);
+        let mut pen = Vector2F::zero();
+        for &ix in &self.visual_order {
+            if ix == fragment_ix {
+                break;
+            }
+            pen += self.fragments[ix].advance;
+        }
+        let caret = fragment_caret(&self.fragments[fragment_ix], self.text(), offset, false);
+        self.line_position(pen + caret)
+    }
+
+    /// The rectangles that visually cover a range of the text, one for each
+    /// run it overlaps, in visual order.
+    ///
+    /// The ends of the range are extended to grapheme boundaries. Each
+    /// rectangle spans the ascent and descent of the run's font.
+    pub fn selection_rects(&self, range: Range<usize>) -> Vec<RectF> {
+        let mut rects = Vec::new();
+        let mut pen = Vector2F::zero();
+        for &ix in &self.visual_order {
+            let fragment = &self.fragments[ix];
+            let selected = fragment.clamp_range(range.clone());
+            if selected.start < selected.end {
+                let from = fragment_caret(fragment, self.text(), selected.start, false);
+                let to = fragment_caret(fragment, self.text(), selected.end, true);
+                let size = self.styles[fragment.style].size;
+                let (ascent, descent) = fragment_extents(fragment, size);
+                rects.push(logical_rect(
+                    self.style().writing_mode,
+                    pen + from,
+                    pen + to,
+                    ascent,
+                    descent,
+                ));
+            }
+            pen += fragment.advance;
+        }
+        rects
+    }
+
+    // The coordinate of a point along the direction of the line.
+    pub(crate) fn line_position(&self, point: Vector2F) -> f32 {
+        match self.style().writing_mode {
+            WritingMode::Horizontal => point.x(),
+            WritingMode::Vertical => point.y(),
+        }
+    }
+
+    // The fragments that overlap the range, found by binary search.
+    fn overlapping_fragments(&self, range: Range<usize>) -> impl Iterator<Item = &LayoutFragment> {
+        let first = self
+            .fragments
+            .partition_point(|fragment| fragment.substr_start + fragment.substr_len <= range.start);
+        self.fragments[first..]
+            .iter()
+            .take_while(move |fragment| fragment.substr_start < range.end)
+    }
+
+    // Lay out part of a fragment, given as a range of the text.
+    //
+    // Glyphs are copied from the fragment between the outermost boundaries
+    // that are safe to break, and only the text outside those is reshaped.
+    fn substr_fragment(&self, fragment: &LayoutFragment, range: Range<usize>) -> LayoutFragment {
+        let start = range.start - fragment.substr_start;
+        let end = range.end - fragment.substr_start;
+        let safe_start = fragment.next_safe_boundary(start, end);
+        let safe_end = fragment.prev_safe_boundary(end, safe_start);
+        if safe_start == safe_end {
+            return self.reshape(fragment, range);
+        }
+        let mut pieces = Vec::new();
+        if start < safe_start {
+            pieces.push(self.reshape(fragment, range.start..fragment.substr_start + safe_start));
+        }
+        pieces.push(fragment.slice(safe_start, safe_end));
+        if safe_end < end {
+            pieces.push(self.reshape(fragment, fragment.substr_start + safe_end..range.end));
+        }
+        LayoutFragment::join(pieces)
+    }
+
+    // Measure part of a fragment, the same way `substr_fragment` lays it out.
+    fn substr_advance(&self, fragment: &LayoutFragment, range: Range<usize>) -> Vector2F {
+        let start = range.start - fragment.substr_start;
+        let end = range.end - fragment.substr_start;
+        let safe_start = fragment.next_safe_boundary(start, end);
+        let safe_end = fragment.prev_safe_boundary(end, safe_start);
+        if safe_start == safe_end {
+            return self.reshape(fragment, range).advance;
+        }
+        let mut advance = fragment.glyphs[fragment.glyph_range(safe_start, safe_end)]
+            .iter()
+            .fold(Vector2F::zero(), |advance, glyph| advance + glyph.advance);
+        if start < safe_start {
+            let edge = range.start..fragment.substr_start + safe_start;
+            advance += self.reshape(fragment, edge).advance;
+        }
+        if safe_end < end {
+            let edge = fragment.substr_start + safe_end..range.end;
+            advance += self.reshape(fragment, edge).advance;
+        }
+        advance
+    }
+
+    fn reshape(&self, fragment: &LayoutFragment, range: Range<usize>) -> LayoutFragment {
+        shape(
+            self.cache.as_ref(),
+            &self.styles[fragment.style],
+            &fragment.font,
+            &fragment.segment(),
+            self.text.as_ref(),
+            range,
+        )
+    }
+}
+
+impl LayoutSession<String> {
+    /// Replace a range of the text, updating the layout.
+    ///
+    /// Only the paragraphs touched by the edit are segmented and itemized
+    /// again. Within them, glyphs are reused up to the nearest boundaries
+    /// that are safe to break on either side of the edit, and only the text
+    /// in between is reshaped. Fragments in other paragraphs are kept. The
+    /// result is the same as creating a session with the edited text.
+    ///
+    /// The replacement takes the style of the text before it, or of the text
+    /// after it when inserted at the start. The ranges of font features are
+    /// moved with the text, and cover the replacement in the same way.
+    ///
+    /// `collection` should be the collection the session was created with.
+    pub fn edit(&mut self, range: Range<usize>, replacement: &str, collection: &FontCollection) {
+        let old_text = self.text.as_str();
+        let delta = replacement.len() as isize - range.len() as isize;
+        // The region of the old text to lay out again: whole paragraphs, and
+        // whole fragments, covering the edit.
+        let mut region_start = paragraph_start(old_text, range.start);
+        // The paragraph after the edit is included even when the edit is at
+        // its start, since it may be joined to the text before.
+        let mut region_end = paragraph_end(old_text, range.end);
+        loop {
+            let start = paragraph_start(old_text, region_start);
+            let end = if is_paragraph_end(old_text, region_end) {
+                region_end
+            } else {
+                paragraph_end(old_text, region_end)
+            };
+            let fragments = self.overlapping_fragments(start..end.max(start + 1));
+            let (start, end) = fragments.fold((start, end), |(start, end), fragment| {
+                (
+                    start.min(fragment.substr_start),
+                    end.max(fragment.substr_start + fragment.substr_len),
+                )
+            });
+            if (start, end) == (region_start, region_end) {
+                break;
+            }
+            region_start = start;
+            region_end = end;
+        }
+        let mut new_text = String::with_capacity(old_text.len() + replacement.len());
+        new_text.push_str(&old_text[..range.start]);
+        new_text.push_str(replacement);
+        new_text.push_str(&old_text[range.end..]);
+        let edit = Edit {
+            start: range.start,
+            new_end: range.start + replacement.len(),
+            delta,
+        };
+
+        for style in &mut self.styles {
+            style.features = edit_features(&style.features, range.clone(), replacement.len());
+        }
+        let style_runs = edit_style_runs(&self.style_runs, range, replacement.len());
+
+        let new_region = region_start..shift_offset(region_end, delta);
+        let region_runs = clip_style_runs(&style_runs, new_region.clone());
+        let mut new_fragments = Vec::new();
+        for segment in segment_text(&new_text[new_region], &self.styles, &region_runs) {
+            let segment = Segment {
+                range: region_start + segment.range.start..region_start + segment.range.end,
+                ..segment
+            };
+            let segment_substr = &new_text[segment.range.clone()];
+            let style = &self.styles[segment.style];
+            for (range, font) in segment_items(collection, segment_substr, &segment, style) {
+                let range = segment.range.start + range.start..segment.range.start + range.end;
+                new_fragments.push(self.relayout(&edit, &new_text, &segment, font, range));
+            }
+        }
+
+        let first = self
+            .fragments
+            .partition_point(|fragment| fragment.substr_start < region_start);
+        let last = self
+            .fragments
+            .partition_point(|fragment| fragment.substr_start < region_end);
+        let new_len = new_fragments.len();
+        self.fragments.splice(first..last, new_fragments);
+        for fragment in &mut self.fragments[first + new_len..] {
+            fragment.substr_start = shift_offset(fragment.substr_start, delta);
+        }
+        self.text = new_text;
+        self.style_runs = style_runs;
+        self.visual_order = fragments_visual_order(&self.fragments);
+        self.substr_fragments.clear();
+        self.substr_visual_order.clear();
+    }
+
+    // Lay out a range of the new text, reusing glyphs of the old layout on
+    // either side of the edit when they have the same properties.
+    //
+    // Glyphs can only be reused up to a boundary that is safe to break in
+    // both the old and the new layout, since the new text next to it may
+    // shape differently (for example, joining Arabic letters). The reshaped
+    // text extends past each boundary to the previous safe boundary of the
+    // old layout, so that the new layout can be checked there, and grows
+    // until both checks pass.
+    fn relayout(
+        &self,
+        edit: &Edit,
+        new_text: &str,
+        segment: &Segment,
+        font: &FontRef,
+        range: Range<usize>,
+    ) -> LayoutFragment {
+        let same_run = |fragment: &&LayoutFragment| {
+            fragment.level == segment.level
+                && fragment.script == segment.script
+                && fragment.orientation == segment.orientation
+                && fragment.locale == segment.locale
+                && fragment.style == segment.style
+                && fragment.placeholder.is_none()
+                && Arc::ptr_eq(&fragment.font.font, &font.font)
+        };
+        // The old fragment before the edit, where the text is unchanged. Its
+        // safe boundaries, in the new text, no earlier than `range.start`.
+        let before = if range.start < edit.start {
+            self.overlapping_fragments(range.start..range.start + 1)
+                .find(same_run)
+                .filter(|fragment| {
+                    let start = range.start - fragment.substr_start;
+                    fragment.next_safe_boundary(start, fragment.substr_len) == start
+                })
+        } else {
+            None
+        };
+        // As below, marks are kept with their base, since HarfBuzz doesn't
+        // flag fallback mark positioning as unsafe to break.
+        let old_text = self.text.as_str();
+        let prev_safe = |offset: usize| match before {
+            Some(fragment) => {
+                let end = fragment.substr_start + fragment.substr_len;
+                let offset = offset.min(end).min(edit.start).min(range.end) - fragment.substr_start;
+                let floor = range.start - fragment.substr_start;
+                let is_safe = |safe| {
+                    safe == floor || is_grapheme_boundary(old_text, fragment.substr_start + safe)
+                };
+                let mut safe = fragment.prev_safe_boundary(offset, floor);
+                while !is_safe(safe) {
+                    safe = fragment.prev_safe_boundary(safe - 1, floor);
+                }
+                fragment.substr_start + safe
+            }
+            None => range.start,
+        };
+        // The old fragment after the edit, where the text is shifted by
+        // `delta`. Its safe boundaries, in the new text, no later than
+        // `range.end`.
+        let old_end = shift_offset(range.end, -edit.delta);
+        let after = if range.end > edit.new_end {
+            self.overlapping_fragments(old_end - 1..old_end)
+                .find(same_run)
+                .filter(|fragment| {
+                    let end = old_end - fragment.substr_start;
+                    fragment.prev_safe_boundary(end, 0) == end
+                })
+        } else {
+            None
+        };
+        let next_safe = |offset: usize| match after {
+            Some(fragment) => {
+                let offset = shift_offset(offset.max(edit.new_end).max(range.start), -edit.delta);
+                let offset = offset.max(fragment.substr_start) - fragment.substr_start;
+                let limit = old_end - fragment.substr_start;
+                let is_safe = |safe| {
+                    safe == limit || is_grapheme_boundary(old_text, fragment.substr_start + safe)
+                };
+                let mut safe = fragment.next_safe_boundary(offset, limit);
+                while !is_safe(safe) {
+                    safe = fragment.next_safe_boundary(safe + 1, limit);
+                }
+                shift_offset(fragment.substr_start + safe, edit.delta)
+            }
+            None => range.end,
+        };
+        let mut head_end = prev_safe(edit.start);
+        let mut tail_start = next_safe(edit.new_end).max(head_end);
+        // The reshaped text between the reused glyphs, if any.
+        let middle = loop {
+            if head_end == tail_start && (head_end == range.start || head_end == range.end) {
+                break None;
+            }
+            let reshape_start = if head_end > range.start {
+                prev_safe(head_end - 1)
+            } else {
+                range.start
+            };
+            let reshape_end = if tail_start < range.end {
+                next_safe(tail_start + 1)
+            } else {
+                range.end
+            };
+            let reshaped = shape(
+                self.cache.as_ref(),
+                &self.styles[segment.style],
+                font,
+                segment,
+                new_text,
+                reshape_start..reshape_end,
+            );
+            // HarfBuzz doesn't flag fallback mark positioning as unsafe to
+            // break, so marks are kept with their base as well.
+            let is_safe = |offset: usize| {
+                let relative = offset - reshape_start;
+                offset == range.start
+                    || offset == range.end
+                    || reshaped.next_safe_boundary(relative, relative + 1) == relative
+                        && is_grapheme_boundary(new_text, offset)
+            };
+            let (head_safe, tail_safe) = (is_safe(head_end), is_safe(tail_start));
+            if head_safe && tail_safe {
+                break Some(reshaped.slice(head_end - reshape_start, tail_start - reshape_start));
+            }
+            if !head_safe {
+                head_end = reshape_start;
+            }
+            if !tail_safe {
+                tail_start = reshape_end;
+            }
+        };
+        let mut pieces = Vec::new();
+        if let Some(fragment) = before.filter(|_| head_end > range.start) {
+            pieces.push(fragment.slice(
+                range.start - fragment.substr_start,
+                head_end - fragment.substr_start,
+            ));
+        }
+        pieces.extend(middle);
+        if let Some(fragment) = after.filter(|_| tail_start < range.end) {
+            let start = shift_offset(tail_start, -edit.delta) - fragment.substr_start;
+            let mut piece = fragment.slice(start, old_end - fragment.substr_start);
+            piece.substr_start = tail_start;
+            pieces.push(piece);
+        }
+        LayoutFragment::join(pieces)
+    }
+}
+
+// A replacement of text, in terms of offsets in the old and new text.
+struct Edit {
+    start: usize,
+    // The end of the replacement in the new text.
+    new_end: usize,
+    // The change in length.
+    delta: isize,
+}
+
+fn shift_offset(offset: usize, delta: isize) -> usize {
+    (offset as isize + delta) as usize
+}
+
+// Characters that end a paragraph, for the bidi algorithm.
+fn is_paragraph_separator(c: char) -> bool {
+    matches!(
+        c,
+        '\n' | '\r' | '\u{1c}' | '\u{1d}' | '\u{1e}' | '\u{85}' | '\u{2029}'
+    )
+}
+
+// The start of the paragraph containing the offset.
+fn paragraph_start(text: &str, offset: usize) -> usize {
+    text[..offset]
+        .char_indices()
+        .rev()
+        .find(|&(_, c)| is_paragraph_separator(c))
+        .map_or(0, |(i, c)| i + c.len_utf8())
+}
+
+// The end of the paragraph containing the text just after the offset,
+// including its separator.
+fn paragraph_end(text: &str, offset: usize) -> usize {
+    text[offset..]
+        .char_indices()
+        .find(|&(_, c)| is_paragraph_separator(c))
+        .map_or(text.len(), |(i, c)| offset + i + c.len_utf8())
+}
+
+fn is_paragraph_end(text: &str, offset: usize) -> bool {
+    offset == text.len() || text[..offset].ends_with(is_paragraph_separator)
+}
+
+// Shape a fragment, going through the cache if there is one.
+fn shape(
+    cache: Option<&LayoutCache>,
+    style: &TextStyle,
+    font: &FontRef,
+    segment: &Segment,
+    text: &str,
+    range: Range<usize>,
+) -> LayoutFragment {
+    if let Some(placeholder) = segment.placeholder {
+        return placeholder_fragment(placeholder, font, segment, range);
+    }
+    match cache {
+        Some(cache) => cache.layout_fragment(style, font, segment, text, range),
+        None => layout_fragment(style, font, segment, text, range),
+    }
+}
+
+// Lay out an inline object. The font is only used to draw an ellipsis next
+// to the object.
+fn placeholder_fragment(
+    placeholder: Placeholder,
+    font: &FontRef,
+    segment: &Segment,
+    range: Range<usize>,
+) -> LayoutFragment {
+    let advance = if segment.orientation == RunOrientation::Horizontal {
+        vec2f(placeholder.width, 0.0)
+    } else {
+        vec2f(0.0, -placeholder.width)
+    };
+    LayoutFragment {
+        substr_start: range.start,
+        substr_len: range.len(),
+        script: segment.script,
+        level: segment.level,
+        orientation: segment.orientation,
+        locale: segment.locale.clone(),
+        style: segment.style,
+        advance,
+        glyphs: vec![FragmentGlyph {
+            cluster: 0,
+            glyph_id: 0,
+            offset: Vector2F::zero(),
+            advance,
+            unsafe_to_break: false,
+        }],
+        font: font.clone(),
+        variations: Vec::new(),
+        synthesis: Synthesis::default(),
+        placeholder: Some(placeholder),
+    }
+}
+
+// The items of a segment by font, relative to its start. An inline object
+// is a single item, with the font of its first character.
+fn segment_items<'a>(
+    collection: &'a FontCollection,
+    text: &'a str,
+    segment: &Segment,
+    style: &'a TextStyle,
+) -> Vec<(Range<usize>, &'a FontRef)> {
+    let mut items: Vec<_> = collection.itemize_with_style(text, style).collect();
+    if segment.placeholder.is_some() {
+        items.truncate(1);
+        if let Some((range, _)) = items.first_mut() {
+            range.end = text.len();
+        }
+    }
+    items
+}
+
+impl LayoutFragment {
+    // The segment properties of this fragment, for reshaping parts of it.
+    fn segment(&self) -> Segment {
+        Segment {
+            range: self.substr_start..self.substr_start + self.substr_len,
+            level: self.level,
+            script: self.script,
+            orientation: self.orientation,
+            locale: self.locale.clone(),
+            style: self.style,
+            placeholder: self.placeholder,
+        }
+    }
+
+    // The part of the range (of the session text) covered by this fragment.
+    fn clamp_range(&self, range: Range<usize>) -> Range<usize> {
+        range.start.max(self.substr_start)..range.end.min(self.substr_start + self.substr_len)
+    }
+
+    // Whether the glyphs are stored in reverse logical order.
+    pub(crate) fn is_rtl(&self) -> bool {
+        self.level & 1 != 0 && self.orientation != RunOrientation::Upright
+    }
+
+    // The index in `glyphs` of the glyph at the given logical position.
+    fn logical_glyph_ix(&self, i: usize) -> usize {
+        if self.is_rtl() {
+            self.glyphs.len() - 1 - i
+        } else {
+            i
+        }
+    }
+
+    // The range of the session text in the cluster of the glyph at the given
+    // index in `glyphs`. The glyphs of a cluster are contiguous.
+    pub(crate) fn cluster_range(&self, glyph_ix: usize) -> Range<usize> {
+        let cluster = self.glyphs[glyph_ix].cluster;
+        let next = if self.is_rtl() {
+            self.glyphs[..glyph_ix]
+                .iter()
+                .rev()
+                .find(|glyph| glyph.cluster != cluster)
+        } else {
+            self.glyphs[glyph_ix..]
+                .iter()
+                .find(|glyph| glyph.cluster != cluster)
+        };
+        let end = next.map_or(self.substr_len, |glyph| glyph.cluster as usize);
+        self.substr_start + cluster as usize..self.substr_start + end
+    }
+
+    // The number of glyphs whose cluster starts before the offset (relative to
+    // the fragment). This relies on clusters being monotonic.
+    fn glyphs_before(&self, offset: usize) -> usize {
+        let (mut lo, mut hi) = (0, self.glyphs.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if (self.glyphs[self.logical_glyph_ix(mid)].cluster as usize) < offset {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    // The first offset at or after `offset` that is safe to break, or `limit`
+    // if there is none before it.
+    pub(crate) fn next_safe_boundary(&self, offset: usize, limit: usize) -> usize {
+        if offset == 0 {
+            return 0;
+        }
+        for i in self.glyphs_before(offset)..self.glyphs.len() {
+            let glyph = &self.glyphs[self.logical_glyph_ix(i)];
+            let cluster = glyph.cluster as usize;
+            if cluster >= limit {
+                break;
+            }
+            if !glyph.unsafe_to_break {
+                return cluster;
+            }
+        }
+        limit
+    }
+
+    // The last offset at or before `offset` that is safe to break, or `floor`
+    // if there is none after it.
+    pub(crate) fn prev_safe_boundary(&self, offset: usize, floor: usize) -> usize {
+        if offset >= self.substr_len {
+            return self.substr_len;
+        }
+        for i in (0..self.glyphs_before(offset + 1)).rev() {
+            let glyph = &self.glyphs[self.logical_glyph_ix(i)];
+            let cluster = glyph.cluster as usize;
+            if cluster <= floor {
+                break;
+            }
+            // Only the first glyph of a cluster decides.
+            let first_of_cluster =
+                i == 0 || self.glyphs[self.logical_glyph_ix(i - 1)].cluster as usize != cluster;
+            if first_of_cluster && !glyph.unsafe_to_break {
+                return cluster;
+            }
+        }
+        floor
+    }
+
+    // The range of `glyphs` covering the text between two cluster boundaries.
+    pub(crate) fn glyph_range(&self, start: usize, end: usize) -> Range<usize> {
+        let (a, b) = (self.glyphs_before(start), self.glyphs_before(end));
+        if self.is_rtl() {
+            self.glyphs.len() - b..self.glyphs.len() - a
+        } else {
+            a..b
+        }
+    }
+
+    // A fragment for the text between two offsets that are safe to break,
+    // reusing the existing glyphs.
+    pub(crate) fn slice(&self, start: usize, end: usize) -> LayoutFragment {
+        let glyph_range = self.glyph_range(start, end);
+        let pen = self.glyphs[..glyph_range.start]
+            .iter()
+            .fold(Vector2F::zero(), |pen, glyph| pen + glyph.advance);
+        let mut advance = Vector2F::zero();
+        let glyphs = self.glyphs[glyph_range]
+            .iter()
+            .map(|glyph| {
+                advance += glyph.advance;
+                FragmentGlyph {
+                    cluster: glyph.cluster - start as u32,
+                    offset: glyph.offset - pen,
+                    ..glyph.clone()
+                }
+            })
+            .collect();
+        LayoutFragment {
+            substr_start: self.substr_start + start,
+            substr_len: end - start,
+            advance,
+            glyphs,
+            ..self.clone_empty()
         }
     }
+
+    // Join contiguous fragments, given in logical order, into one.
+    pub(crate) fn join(pieces: Vec<LayoutFragment>) -> LayoutFragment {
+        let first = &pieces[0];
+        let start = first.substr_start;
+        let rtl = first.is_rtl();
+        let mut result = LayoutFragment {
+            substr_start: start,
+            substr_len: pieces.iter().map(|piece| piece.substr_len).sum(),
+            ..first.clone_empty()
+        };
+        let mut add_piece = |piece: &LayoutFragment| {
+            let rebase = (piece.substr_start - start) as u32;
+            for glyph in &piece.glyphs {
+                result.glyphs.push(FragmentGlyph {
+                    cluster: glyph.cluster + rebase,
+                    offset: glyph.offset + result.advance,
+                    ..glyph.clone()
+                });
+            }
+            result.advance += piece.advance;
+        };
+        if rtl {
+            pieces.iter().rev().for_each(&mut add_piece);
+        } else {
+            pieces.iter().for_each(&mut add_piece);
+        }
+        result
+    }
+
+    // A copy of the fragment's properties, without any glyphs.
+    fn clone_empty(&self) -> LayoutFragment {
+        LayoutFragment {
+            substr_start: self.substr_start,
+            substr_len: 0,
+            script: self.script,
+            level: self.level,
+            orientation: self.orientation,
+            locale: self.locale.clone(),
+            style: self.style,
+            advance: Vector2F::zero(),
+            glyphs: Vec::new(),
+            font: self.font.clone(),
+            variations: self.variations.clone(),
+            synthesis: self.synthesis,
+            placeholder: self.placeholder,
+        }
+    }
+}
+
+fn fragments_visual_order(fragments: &[LayoutFragment]) -> Vec<usize> {
+    let levels: Vec<u8> = fragments.iter().map(|fragment| fragment.level).collect();
+    visual_order(&levels)
 }
 
 impl<'a> Iterator for LayoutRangeIter<'a> {
     type Item = LayoutRun<'a>;
 
     fn next(&mut self) -> Option<LayoutRun<'a>> {
-        if self.fragment_ix == self.fragments.len() {
+        if self.ix == self.visual_order.len() {
             None
         } else {
-            let fragment = &self.fragments[self.fragment_ix];
-            self.fragment_ix += 1;
+            let fragment = &self.fragments[self.visual_order[self.ix]];
+            self.ix += 1;
             let offset = self.offset;
             self.offset += fragment.advance;
-            Some(LayoutRun { offset, fragment })
+            Some(LayoutRun {
+                offset,
+                fragment,
+                style: &self.styles[fragment.style],
+            })
         }
     }
 }
@@ -175,6 +1392,78 @@ impl<'a> LayoutRun<'a> {
         &self.fragment.font
     }
 
+    /// The range of the session text covered by this run, in logical order.
+    pub fn text_range(&self) -> Range<usize> {
+        let start = self.fragment.substr_start;
+        start..start + self.fragment.substr_len
+    }
+
+    /// The bidi embedding level of this run; odd levels are right-to-left.
+    pub fn bidi_level(&self) -> u8 {
+        self.fragment.level
+    }
+
+    pub fn orientation(&self) -> RunOrientation {
+        self.fragment.orientation
+    }
+
+    /// The locale used to shape this run, if any.
+    pub fn locale(&self) -> Option<&Locale> {
+        self.fragment.locale.as_ref()
+    }
+
+    /// The index of the style of this run, in the styles of the session.
+    pub fn style_index(&self) -> usize {
+        self.fragment.style
+    }
+
+    /// The metrics of the run's font, scaled to the size of its style.
+    pub fn metrics(&self) -> FontMetrics {
+        font_metrics(&self.fragment.font, self.style.size)
+    }
+
+    /// The underline position and thickness of the run's font. To draw one
+    /// line across runs, use `DecorationLine::underline`.
+    pub fn underline(&self) -> DecorationMetrics {
+        underline_metrics(&self.fragment.font, self.style.size)
+    }
+
+    /// The strikeout position and thickness of the run's font. To draw one
+    /// line across runs, use `DecorationLine::strikeout`.
+    pub fn strikeout(&self) -> DecorationMetrics {
+        strikeout_metrics(&self.fragment.font, self.style.size)
+    }
+
+    pub(crate) fn size(&self) -> f32 {
+        self.style.size
+    }
+
+    /// The inline object laid out by this run, if any. Such a run has no
+    /// glyphs; the object goes at the run's offset, with the run's advance.
+    pub fn placeholder(&self) -> Option<Placeholder> {
+        self.fragment.placeholder
+    }
+
+    /// The position of the start of the run.
+    pub fn offset(&self) -> Vector2F {
+        self.offset
+    }
+
+    pub fn advance(&self) -> Vector2F {
+        self.fragment.advance
+    }
+
+    /// The instance of a variable font used for this run, as a value for
+    /// each axis of the font. This is empty for fonts that are not variable.
+    pub fn variations(&self) -> &[FontVariation] {
+        &self.fragment.variations
+    }
+
+    /// The faux bold and italic to apply when drawing this run.
+    pub fn synthesis(&self) -> Synthesis {
+        self.fragment.synthesis
+    }
+
     pub fn glyphs(&self) -> RunIter<'a> {
         RunIter {
             offset: self.offset,
@@ -182,25 +1471,314 @@ impl<'a> LayoutRun<'a> {
             glyph_ix: 0,
         }
     }
+
+    /// Iterate through the clusters of the run, in visual order.
+    pub fn clusters(&self) -> ClusterIter<'a> {
+        ClusterIter {
+            offset: self.offset,
+            fragment: self.fragment,
+            glyph_ix: 0,
+        }
+    }
 }
 
 impl<'a> Iterator for RunIter<'a> {
     type Item = GlyphInfo;
 
     fn next(&mut self) -> Option<GlyphInfo> {
-        if self.glyph_ix == self.fragment.glyphs.len() {
+        if self.glyph_ix == self.fragment.glyphs.len() || self.fragment.placeholder.is_some() {
             None
         } else {
             let glyph = &self.fragment.glyphs[self.glyph_ix];
+            let cluster = self.fragment.cluster_range(self.glyph_ix);
             self.glyph_ix += 1;
             Some(GlyphInfo {
                 glyph_id: glyph.glyph_id,
                 offset: self.offset + glyph.offset,
+                advance: glyph.advance,
+                cluster,
+                unsafe_to_break: glyph.unsafe_to_break,
             })
         }
     }
 }
 
+impl<'a> Iterator for ClusterIter<'a> {
+    type Item = ClusterInfo;
+
+    fn next(&mut self) -> Option<ClusterInfo> {
+        let glyphs = &self.fragment.glyphs;
+        if self.glyph_ix == glyphs.len() {
+            return None;
+        }
+        let start = self.glyph_ix;
+        let cluster = glyphs[start].cluster;
+        let offset = self.offset;
+        while self.glyph_ix < glyphs.len() && glyphs[self.glyph_ix].cluster == cluster {
+            self.offset += glyphs[self.glyph_ix].advance;
+            self.glyph_ix += 1;
+        }
+        let glyph_range = if self.fragment.placeholder.is_some() {
+            0..0
+        } else {
+            start..self.glyph_ix
+        };
+        Some(ClusterInfo {
+            text_range: self.fragment.cluster_range(start),
+            glyph_range,
+            offset,
+            advance: self.offset - offset,
+        })
+    }
+}
+
+/// Split the text into segments that can each be shaped in one piece (before
+/// font itemization).
+fn segment_text(text: &str, styles: &[TextStyle], style_runs: &[StyleSpan]) -> Vec<Segment> {
+    let base_style = &styles[0];
+    let levels = bidi_levels(text, base_style.direction);
+    let mut segments = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        let level = levels[i];
+        // Segments end with their paragraph, so that a paragraph is laid out
+        // the same way on its own, as when it is edited.
+        let para_end = paragraph_end(text, i);
+        let level_end = levels[i..para_end]
+            .iter()
+            .position(|&l| l != level)
+            .map(|len| i + len)
+            .unwrap_or(para_end);
+        while i < level_end {
+            let (script, script_len) = get_script_run(&text[i..level_end]);
+            let script_end = i + script_len;
+            while i < script_end {
+                let (orientation, orientation_len) = match base_style.writing_mode {
+                    WritingMode::Horizontal => (RunOrientation::Horizontal, script_end - i),
+                    WritingMode::Vertical => get_orientation_run(&text[i..script_end]),
+                };
+                let orientation_end = i + orientation_len;
+                while i < orientation_end {
+                    let run_ix = style_runs.partition_point(|run| run.range.end <= i);
+                    let run = &style_runs[run_ix];
+                    let end = run.range.end.min(orientation_end);
+                    let locale = styles[run.style].locales.locale_for_script(script);
+                    segments.push(Segment {
+                        range: i..end,
+                        level,
+                        script,
+                        orientation,
+                        locale: locale.cloned(),
+                        style: run.style,
+                        placeholder: run.placeholder,
+                    });
+                    i = end;
+                }
+            }
+        }
+    }
+    segments
+}
+
+/// Resolve style spans, which may overlap or leave gaps, into contiguous runs
+/// covering the text. Later spans take precedence, and gaps use style 0.
+/// Each inline object is a run of its own.
+fn style_runs(len: usize, style_count: usize, spans: &[StyleSpan]) -> Vec<StyleSpan> {
+    if spans.is_empty() {
+        return if len == 0 {
+            Vec::new()
+        } else {
+            vec![StyleSpan::new(0..len, 0)]
+        };
+    }
+    // The offsets where spans start and end, with the index of the span.
+    let mut boundaries = Vec::with_capacity(2 * spans.len());
+    for (ix, span) in spans.iter().enumerate() {
+        assert!(
+            span.style < style_count,
+            "no style with index {}",
+            span.style
+        );
+        let end = span.range.end.min(len);
+        let start = span.range.start.min(end);
+        if start < end {
+            boundaries.push((start, ix));
+            boundaries.push((end, ix));
+        }
+    }
+    boundaries.sort_unstable_by_key(|&(offset, _)| offset);
+    // Sweep through the boundaries, keeping track of the spans that cover
+    // the text between them. The last of those applies.
+    let mut active: BTreeSet<usize> = BTreeSet::new();
+    let mut runs = Vec::new();
+    let mut start = 0;
+    let mut prev_owner = None;
+    let mut boundaries = boundaries.into_iter().peekable();
+    loop {
+        let end = boundaries.peek().map_or(len, |&(offset, _)| offset);
+        if start < end {
+            let owner = active.iter().next_back().copied();
+            let (style, placeholder) = match owner {
+                Some(ix) => (spans[ix].style, spans[ix].placeholder),
+                None => (0, None),
+            };
+            let same_object = owner == prev_owner;
+            push_style_run(&mut runs, start..end, style, placeholder, same_object);
+            start = end;
+            prev_owner = owner;
+        }
+        match boundaries.next() {
+            // Each span is reached at its start first, then at its end.
+            Some((_, ix)) => {
+                if !active.insert(ix) {
+                    active.remove(&ix);
+                }
+            }
+            None => break,
+        }
+    }
+    runs
+}
+
+/// Update style runs for replacing a range of the text with `len` bytes,
+/// which take the style of the text before them.
+fn edit_style_runs(runs: &[StyleSpan], range: Range<usize>, len: usize) -> Vec<StyleSpan> {
+    let delta = len as isize - range.len() as isize;
+    let inserted_style = match range.start.checked_sub(1) {
+        Some(before) => runs.iter().find(|run| run.range.contains(&before)),
+        None => runs.first(),
+    }
+    .map_or(0, |run| run.style);
+    let mut new_runs = Vec::new();
+    for run in runs {
+        let end = run.range.end.min(range.start);
+        push_style_run(
+            &mut new_runs,
+            run.range.start..end,
+            run.style,
+            run.placeholder,
+            false,
+        );
+    }
+    push_style_run(
+        &mut new_runs,
+        range.start..range.start + len,
+        inserted_style,
+        None,
+        false,
+    );
+    for run in runs {
+        let start = shift_offset(run.range.start.max(range.end), delta);
+        let end = shift_offset(run.range.end.max(range.end), delta);
+        push_style_run(&mut new_runs, start..end, run.style, run.placeholder, false);
+    }
+    new_runs
+}
+
+// Move the ranges of the features for an edit, as `edit_style_runs` does for
+// style runs. Features left with an empty range are removed.
+fn edit_features(features: &[FontFeature], range: Range<usize>, len: usize) -> Vec<FontFeature> {
+    let delta = len as isize - range.len() as isize;
+    let before = range.start.saturating_sub(1);
+    features
+        .iter()
+        .filter_map(|feature| {
+            let r = match &feature.range {
+                Some(r) => r,
+                None => return Some(feature.clone()),
+            };
+            let kept = r.start..r.end.min(range.start);
+            let inserted = if r.contains(&before) {
+                range.start..range.start + len
+            } else {
+                range.start..range.start
+            };
+            let moved = shift_offset(r.start.max(range.end), delta)
+                ..shift_offset(r.end.max(range.end), delta);
+            // The parts are in order, and adjacent when not empty.
+            let parts = [kept, inserted, moved];
+            let mut parts = parts.iter().filter(|part| !part.is_empty());
+            let first = parts.next()?;
+            let end = parts.next_back().map_or(first.end, |last| last.end);
+            Some(FontFeature {
+                range: Some(first.start..end),
+                ..feature.clone()
+            })
+        })
+        .collect()
+}
+
+// Add a range to the end of the style runs, extending the last run if it has
+// the same style. Inline objects are only extended when `same_object` is set.
+fn push_style_run(
+    runs: &mut Vec<StyleSpan>,
+    range: Range<usize>,
+    style: usize,
+    placeholder: Option<Placeholder>,
+    same_object: bool,
+) {
+    if range.start >= range.end {
+        return;
+    }
+    match runs.last_mut() {
+        Some(run)
+            if run.style == style && run.placeholder.is_none() && placeholder.is_none()
+                || same_object =>
+        {
+            run.range.end = range.end
+        }
+        _ => runs.push(StyleSpan {
+            range,
+            style,
+            placeholder,
+        }),
+    }
+}
+
+/// The style runs overlapping a range of the text, relative to its start.
+fn clip_style_runs(runs: &[StyleSpan], range: Range<usize>) -> Vec<StyleSpan> {
+    runs.iter()
+        .filter(|run| run.range.start < range.end && run.range.end > range.start)
+        .map(|run| StyleSpan {
+            range: run.range.start.max(range.start) - range.start
+                ..run.range.end.min(range.end) - range.start,
+            ..run.clone()
+        })
+        .collect()
+}
+
+/// Figure out whether the initial part of the buffer is set upright or
+/// sideways in vertical text, and also return the length of the run.
+///
+/// Characters with the Tu and Tr values of Vertical_Orientation are set
+/// upright, relying on the `vert` feature for the transformed glyphs.
+fn get_orientation_run(text: &str) -> (RunOrientation, usize) {
+    let mut char_iter = text.chars();
+    if let Some(cp) = char_iter.next() {
+        let orientation = vertical_orientation(cp);
+        let mut len = cp.len_utf8();
+        for cp in char_iter {
+            // Combining marks stay with their base.
+            if lookup_script(cp.into()) != HB_SCRIPT_INHERITED
+                && vertical_orientation(cp) != orientation
+            {
+                break;
+            }
+            len += cp.len_utf8();
+        }
+        (orientation, len)
+    } else {
+        (RunOrientation::Upright, 0)
+    }
+}
+
+fn vertical_orientation(c: char) -> RunOrientation {
+    match char_orientation(c) {
+        Orientation::Rotated => RunOrientation::Sideways,
+        _ => RunOrientation::Upright,
+    }
+}
+
 /// Figure out the script for the initial part of the buffer, and also
 /// return the length of the run where that script is valid.
 pub(crate) fn get_script_run(text: &str) -> (hb_script_t, usize) {
@@ -208,7 +1786,7 @@ pub(crate) fn get_script_run(text: &str) -> (hb_script_t, usize) {
     if let Some(cp) = char_iter.next() {
         let mut current_script = lookup_script(cp.into());
         let mut len = cp.len_utf8();
-        while let Some(cp) = char_iter.next() {
+        for cp in char_iter {
             let script = lookup_script(cp.into());
             if script != current_script {
                 if current_script == HB_SCRIPT_INHERITED || current_script == HB_SCRIPT_COMMON {
@@ -228,6 +1806,7 @@ pub(crate) fn get_script_run(text: &str) -> (hb_script_t, usize) {
     }
 }
 
+#[allow(unused)]
 fn debug_script_runs(text: &str) {
     let mut text_substr = text;
     while !text_substr.is_empty() {
@@ -236,3 +1815,667 @@ fn debug_script_runs(text: &str) {
         text_substr = &text_substr[len..];
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+
+    use font_kit::family_name::FamilyName;
+    use font_kit::properties::Properties;
+    use font_kit::source::SystemSource;
+    use pathfinder_geometry::rect::RectF;
+    use pathfinder_geometry::vector::Vector2F;
+
+    use super::{
+        edit_features, edit_style_runs, style_runs, EllipsisPosition, LayoutSession, Placeholder,
+        StyleSpan,
+    };
+    use crate::{FontCollection, FontFamily, FontFeature, RunOrientation, TextStyle, WritingMode};
+
+    // The runs of a layout: their text ranges, bidi levels, and glyph ids,
+    // clusters and positions (rounded, since reused glyphs are placed by
+    // adding up advances in a different order).
+    type Summary = Vec<(Range<usize>, u8, Vec<(u32, Range<usize>, (i32, i32))>)>;
+
+    fn collection() -> FontCollection {
+        let font = SystemSource::new()
+            .select_best_match(&[FamilyName::SansSerif], &Properties::new())
+            .unwrap()
+            .load()
+            .unwrap();
+        let mut collection = FontCollection::new();
+        collection.add_family(FontFamily::new_from_font(font));
+        collection
+    }
+
+    fn session(text: &str) -> LayoutSession<String> {
+        LayoutSession::create(text.to_string(), &TextStyle::new(16.0), &collection())
+    }
+
+    #[test]
+    fn empty_substr() {
+        let mut session = session("abc");
+        assert_eq!(session.iter_substr(1..1).count(), 0);
+        assert_eq!(session.iter_justified(1..1, 100.0).count(), 0);
+        assert_eq!(session.iter_substr(3..3).count(), 0);
+    }
+
+    #[test]
+    fn empty_advance() {
+        let session = session("abc");
+        assert_eq!(session.advance_substr(1..1), Vector2F::zero());
+        assert_eq!(session.advance_substr(0..0), Vector2F::zero());
+        assert_eq!(
+            session.advance_substr(0..3),
+            session.advance_substr(0..2) + session.advance_substr(2..3)
+        );
+    }
+
+    #[test]
+    fn advance_substr_matches_iter_substr() {
+        let text = "سلام عليكم office";
+        let mut session = session(text);
+        // Joined Arabic letters and ligatures are unsafe to break.
+        let unsafe_edges = session
+            .iter_all()
+            .flat_map(|run| run.glyphs())
+            .filter(|glyph| glyph.unsafe_to_break)
+            .count();
+        assert!(unsafe_edges > 0);
+        let boundaries: Vec<usize> = (0..=text.len())
+            .filter(|&i| text.is_char_boundary(i))
+            .collect();
+        for &start in &boundaries {
+            for &end in boundaries.iter().filter(|&&end| end >= start) {
+                let advance = session.advance_substr(start..end);
+                let total = session
+                    .iter_substr(start..end)
+                    .fold(Vector2F::zero(), |total, run| total + run.advance());
+                assert!(
+                    (advance - total).length() < 1e-3,
+                    "{:?}: {:?} != {:?}",
+                    start..end,
+                    advance,
+                    total
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn empty_bounds() {
+        let session = session("abc");
+        let bounds = session.bounds_substr(1..1);
+        assert_eq!(bounds.logical, RectF::default());
+        assert_eq!(bounds.ink, RectF::default());
+        assert!(session.bounds_substr(0..2).ink.width() > 0.0);
+    }
+
+    // The text ranges and bidi levels of the runs of a substring, in
+    // visual order.
+    fn substr_runs(
+        session: &mut LayoutSession<String>,
+        range: Range<usize>,
+    ) -> Vec<(Range<usize>, u8)> {
+        session
+            .iter_substr(range)
+            .map(|run| (run.text_range(), run.bidi_level()))
+            .collect()
+    }
+
+    #[test]
+    fn substr_trailing_whitespace_takes_paragraph_level() {
+        let mut ltr = session("abc שלום עולם def 123");
+        // The space after "שלום" is between two Hebrew words in the
+        // paragraph, but ends the line here.
+        assert_eq!(
+            substr_runs(&mut ltr, 0..13),
+            vec![(0..4, 0), (4..12, 1), (12..13, 0)]
+        );
+        // Whitespace in the middle of the line keeps its level.
+        assert_eq!(
+            substr_runs(&mut ltr, 0..22),
+            vec![(0..4, 0), (4..21, 1), (21..22, 0)]
+        );
+        // In a right-to-left paragraph, the end of the line is on the left.
+        let mut rtl = session("שלום abc def");
+        assert_eq!(
+            substr_runs(&mut rtl, 0..13),
+            vec![(12..13, 1), (9..12, 2), (0..9, 1)]
+        );
+    }
+
+    // The text ranges of the clusters of each run of a substring, checking
+    // that they match the clusters of their glyphs.
+    fn substr_clusters(
+        session: &mut LayoutSession<String>,
+        range: Range<usize>,
+    ) -> Vec<Vec<Range<usize>>> {
+        session
+            .iter_substr(range)
+            .map(|run| {
+                let glyphs: Vec<_> = run.glyphs().collect();
+                run.clusters()
+                    .map(|cluster| {
+                        for glyph in &glyphs[cluster.glyph_range.clone()] {
+                            assert_eq!(glyph.cluster, cluster.text_range);
+                        }
+                        cluster.text_range
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn substr_clusters_are_session_ranges() {
+        let mut session = session("abc office שלום");
+        // "ffi" is one glyph, and the Hebrew clusters are in visual order.
+        assert_eq!(
+            substr_clusters(&mut session, 4..19),
+            vec![
+                vec![4..5, 5..8, 8..9, 9..10, 10..11],
+                vec![17..19, 15..17, 13..15, 11..13],
+            ]
+        );
+        assert_eq!(
+            substr_clusters(&mut session, 8..15),
+            vec![vec![8..9, 9..10, 10..11], vec![13..15, 11..13]]
+        );
+        for run in session.iter_substr(4..19) {
+            let mut glyph_ix = 0;
+            let mut advance = Vector2F::zero();
+            for cluster in run.clusters() {
+                assert_eq!(cluster.glyph_range.start, glyph_ix);
+                glyph_ix = cluster.glyph_range.end;
+                assert_eq!(cluster.offset, run.offset() + advance);
+                advance += cluster.advance;
+            }
+            assert_eq!(glyph_ix, run.glyphs().count());
+            assert_eq!(advance, run.advance());
+        }
+    }
+
+    #[test]
+    fn selection_rects_ltr() {
+        let session = session("abc def");
+        let rects = session.selection_rects(1..5);
+        assert_eq!(rects.len(), 1);
+        assert_eq!(rects[0].min_x(), session.advance_for_offset(1));
+        assert_eq!(rects[0].max_x(), session.advance_for_offset(5));
+        assert!(rects[0].height() > 0.0);
+    }
+
+    #[test]
+    fn selection_rects_across_bidi_boundary() {
+        let session = session("abc שלום def");
+        // "c " on the left, and the start of the Hebrew text on its right.
+        let rects = session.selection_rects(2..8);
+        assert_eq!(rects.len(), 2);
+        assert_eq!(rects[0].min_x(), session.advance_for_offset(2));
+        assert_eq!(rects[1].min_x(), session.advance_for_offset(8));
+        assert_eq!(rects[1].max_x(), session.advance_for_offset(4));
+        // The end of the Hebrew text is not selected.
+        assert!(rects[0].max_x() < rects[1].min_x());
+    }
+
+    #[test]
+    fn selection_rects_empty_range() {
+        let session = session("abc שלום def");
+        assert!(session.selection_rects(2..2).is_empty());
+        assert!(session.selection_rects(6..6).is_empty());
+        assert!(session.selection_rects(16..16).is_empty());
+    }
+
+    // The elided range, the text ranges of the runs in visual order, and
+    // the total advance of the text truncated to `width`.
+    fn truncate(
+        text: &str,
+        width: f32,
+        position: EllipsisPosition,
+    ) -> (Option<Range<usize>>, Vec<Range<usize>>, f32) {
+        let collection = collection();
+        let mut session = session(text);
+        let truncation = session.iter_truncated(width, position, &collection);
+        let elided = truncation.elided;
+        let runs: Vec<_> = truncation.runs.collect();
+        let total = runs.iter().map(|run| run.advance().x()).sum();
+        (
+            elided,
+            runs.iter().map(|run| run.text_range()).collect(),
+            total,
+        )
+    }
+
+    fn ellipsis_width() -> f32 {
+        session("\u{2026}").advance_substr(0..3).x()
+    }
+
+    const POSITIONS: [EllipsisPosition; 3] = [
+        EllipsisPosition::Start,
+        EllipsisPosition::Middle,
+        EllipsisPosition::End,
+    ];
+
+    #[test]
+    fn truncation_not_needed() {
+        let text = "hello world";
+        let width = session(text).advance_substr(0..11).x();
+        for &position in &POSITIONS {
+            let (elided, runs, total) = truncate(text, width, position);
+            assert_eq!(elided, None);
+            assert_eq!(runs.len(), 1);
+            assert_eq!(runs[0], 0..11);
+            assert_eq!(total, width);
+        }
+    }
+
+    #[test]
+    fn truncate_end() {
+        let text = "hello world again";
+        let width = session(text).advance_substr(0..9).x() + ellipsis_width() + 0.1;
+        let (elided, runs, total) = truncate(text, width, EllipsisPosition::End);
+        assert_eq!(elided, Some(9..17));
+        assert_eq!(runs, vec![0..9, 9..9]);
+        assert!(total <= width);
+        // Whitespace before the ellipsis is elided too.
+        let width = session(text).advance_substr(0..6).x() + ellipsis_width() + 0.1;
+        let (elided, runs, _) = truncate(text, width, EllipsisPosition::End);
+        assert_eq!(elided, Some(5..17));
+        assert_eq!(runs, vec![0..5, 5..5]);
+    }
+
+    #[test]
+    fn truncate_start() {
+        let text = "hello world again";
+        let width = session(text).advance_substr(13..17).x() + ellipsis_width() + 0.1;
+        let (elided, runs, total) = truncate(text, width, EllipsisPosition::Start);
+        assert_eq!(elided, Some(0..13));
+        assert_eq!(runs, vec![0..0, 13..17]);
+        assert!(total <= width);
+        // Whitespace after the ellipsis is elided too.
+        let width = session(text).advance_substr(11..17).x() + ellipsis_width() + 0.1;
+        let (elided, runs, _) = truncate(text, width, EllipsisPosition::Start);
+        assert_eq!(elided, Some(0..12));
+        assert_eq!(runs, vec![0..0, 12..17]);
+    }
+
+    #[test]
+    fn truncate_middle() {
+        let text = "hello world again";
+        let width = session(text).advance_substr(0..17).x() * 0.6;
+        let (elided, runs, total) = truncate(text, width, EllipsisPosition::Middle);
+        let elided = elided.unwrap();
+        assert!(elided.start > 0 && elided.end < text.len());
+        assert_eq!(
+            runs,
+            vec![0..elided.start, elided.start..elided.start, elided.end..17]
+        );
+        assert!(total <= width);
+        assert!(!text[..elided.start].ends_with(' '));
+        assert!(!text[elided.end..].starts_with(' '));
+    }
+
+    #[test]
+    fn truncation_keeps_graphemes_whole() {
+        let text = "ae\u{301}i\u{301}o\u{301}u\u{301}";
+        let full = session(text).advance_substr(0..text.len()).x();
+        for &position in &POSITIONS {
+            for step in 1..20 {
+                let width = ellipsis_width() + full * step as f32 / 20.0;
+                let (elided, _, total) = truncate(text, width, position);
+                if let Some(elided) = elided {
+                    assert!(!text[elided.start..].starts_with('\u{301}'));
+                    assert!(!text[elided.end..].starts_with('\u{301}'));
+                    assert!(total <= width, "{:?} at {}", position, width);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncate_to_less_than_an_ellipsis() {
+        let text = "hello";
+        let width = 0.5 * ellipsis_width();
+        for &position in &POSITIONS {
+            let (elided, runs, total) = truncate(text, width, position);
+            // Only the ellipsis is left, even though it doesn't fit.
+            assert_eq!(elided, Some(0..5));
+            assert_eq!(runs.len(), 1);
+            assert_eq!(runs[0], 0..0);
+            assert_eq!(total, ellipsis_width());
+        }
+    }
+
+    #[test]
+    fn vertical_orientation_runs() {
+        let style = TextStyle {
+            writing_mode: WritingMode::Vertical,
+            ..TextStyle::new(16.0)
+        };
+        let session = LayoutSession::create("ab§©c\u{301}d".to_string(), &style, &collection());
+        let runs: Vec<_> = session
+            .iter_all()
+            .map(|run| (run.text_range(), run.orientation()))
+            .collect();
+        // Latin letters are set sideways, and the symbols upright. The
+        // combining mark stays with its base.
+        assert_eq!(
+            runs,
+            vec![
+                (0..2, RunOrientation::Sideways),
+                (2..6, RunOrientation::Upright),
+                (6..10, RunOrientation::Sideways),
+            ]
+        );
+        for run in session.iter_all() {
+            assert_eq!(run.advance().x(), 0.0);
+            assert!(run.advance().y() < 0.0);
+        }
+    }
+
+    fn runs(runs: &[StyleSpan]) -> Vec<(Range<usize>, usize)> {
+        runs.iter()
+            .map(|run| (run.range.clone(), run.style))
+            .collect()
+    }
+
+    #[test]
+    fn style_runs_cover_the_text() {
+        assert_eq!(runs(&style_runs(5, 1, &[])), vec![(0..5, 0)]);
+        assert_eq!(runs(&style_runs(0, 1, &[])), vec![]);
+        let spans = [StyleSpan::new(1..3, 1), StyleSpan::new(4..10, 2)];
+        assert_eq!(
+            runs(&style_runs(6, 3, &spans)),
+            vec![(0..1, 0), (1..3, 1), (3..4, 0), (4..6, 2)]
+        );
+    }
+
+    #[test]
+    fn later_style_spans_take_precedence() {
+        let spans = [StyleSpan::new(0..6, 1), StyleSpan::new(2..4, 2)];
+        assert_eq!(
+            runs(&style_runs(6, 3, &spans)),
+            vec![(0..2, 1), (2..4, 2), (4..6, 1)]
+        );
+        let spans = [StyleSpan::new(2..4, 2), StyleSpan::new(0..6, 1)];
+        assert_eq!(runs(&style_runs(6, 3, &spans)), vec![(0..6, 1)]);
+    }
+
+    #[test]
+    fn adjacent_style_spans_merge() {
+        let spans = [StyleSpan::new(0..2, 1), StyleSpan::new(2..4, 1)];
+        assert_eq!(runs(&style_runs(4, 2, &spans)), vec![(0..4, 1)]);
+        let spans = [StyleSpan::new(0..2, 0), StyleSpan::new(3..3, 1)];
+        assert_eq!(runs(&style_runs(4, 2, &spans)), vec![(0..4, 0)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "no style with index 2")]
+    fn style_span_out_of_range() {
+        style_runs(4, 2, &[StyleSpan::new(0..2, 2)]);
+    }
+
+    #[test]
+    fn edit_style_runs_takes_style_before() {
+        let before = style_runs(6, 2, &[StyleSpan::new(0..3, 1)]);
+        // Inserting at the end of a run extends it.
+        assert_eq!(
+            runs(&edit_style_runs(&before, 3..3, 2)),
+            vec![(0..5, 1), (5..8, 0)]
+        );
+        // Inserting at the start takes the style of the first run.
+        assert_eq!(
+            runs(&edit_style_runs(&before, 0..0, 2)),
+            vec![(0..5, 1), (5..8, 0)]
+        );
+        // Replacing text across runs.
+        assert_eq!(
+            runs(&edit_style_runs(&before, 2..4, 1)),
+            vec![(0..3, 1), (3..5, 0)]
+        );
+        // Deleting a whole run joins the runs around it.
+        let before = style_runs(6, 2, &[StyleSpan::new(2..4, 1)]);
+        assert_eq!(runs(&edit_style_runs(&before, 2..4, 0)), vec![(0..4, 0)]);
+    }
+
+    fn feature_ranges(features: &[FontFeature]) -> Vec<Option<Range<usize>>> {
+        features
+            .iter()
+            .map(|feature| feature.range.clone())
+            .collect()
+    }
+
+    #[test]
+    fn edit_features_moves_ranges() {
+        let features = [
+            FontFeature::with_range(b"liga", 0, 2..6),
+            FontFeature::new(b"kern", 0),
+        ];
+        let edit = |range, len| feature_ranges(&edit_features(&features, range, len));
+        // Text before the range shifts it, and text inside it extends it.
+        assert_eq!(edit(0..0, 1), vec![Some(3..7), None]);
+        assert_eq!(edit(3..3, 2), vec![Some(2..8), None]);
+        // Inserted text takes the settings of the text before it.
+        assert_eq!(edit(2..2, 1), vec![Some(3..7), None]);
+        assert_eq!(edit(6..6, 1), vec![Some(2..7), None]);
+        assert_eq!(edit(7..7, 1), vec![Some(2..6), None]);
+        // Deleted text is clipped from the range.
+        assert_eq!(edit(1..4, 0), vec![Some(1..3), None]);
+        assert_eq!(edit(4..8, 1), vec![Some(2..5), None]);
+        // A range with all its text deleted is removed.
+        assert_eq!(edit(2..6, 0), vec![None]);
+        assert_eq!(edit(1..7, 3), vec![None]);
+    }
+
+    #[test]
+    fn edit_ranged_features() {
+        let collection = collection();
+        let no_ligatures = |range: Option<Range<usize>>| TextStyle {
+            features: range
+                .map(|range| FontFeature::with_range(b"liga", 0, range))
+                .into_iter()
+                .collect(),
+            ..TextStyle::new(16.0)
+        };
+        let style = no_ligatures(Some(2..6));
+        for (range, replacement, new_range) in &[
+            (0..0, "x", Some(3..7)),
+            (0..2, "", Some(0..4)),
+            (1..1, "f", Some(3..7)),
+            (2..2, "ffi", Some(5..9)),
+            (3..3, "ff", Some(2..8)),
+            (1..4, "ffi", Some(4..6)),
+            (6..6, " office", Some(2..13)),
+            (2..6, "", None),
+        ] {
+            let new_style = no_ligatures(new_range.clone());
+            check_styled_edit(
+                &collection,
+                &style,
+                "office",
+                range.clone(),
+                replacement,
+                &new_style,
+            );
+        }
+    }
+
+    fn object(range: Range<usize>) -> StyleSpan {
+        StyleSpan {
+            placeholder: Some(Placeholder {
+                width: 10.0,
+                ascent: 8.0,
+                descent: -2.0,
+            }),
+            ..StyleSpan::new(range, 0)
+        }
+    }
+
+    fn objects(runs: &[StyleSpan]) -> Vec<Range<usize>> {
+        runs.iter()
+            .filter(|run| run.placeholder.is_some())
+            .map(|run| run.range.clone())
+            .collect()
+    }
+
+    #[test]
+    fn inline_objects_are_separate_runs() {
+        let spans = [object(2..3), object(3..4)];
+        let resolved = style_runs(6, 1, &spans);
+        assert_eq!(
+            runs(&resolved),
+            vec![(0..2, 0), (2..3, 0), (3..4, 0), (4..6, 0)]
+        );
+        assert_eq!(objects(&resolved), vec![2..3, 3..4]);
+    }
+
+    #[test]
+    fn inline_object_split_by_a_later_span() {
+        let spans = [object(0..6), StyleSpan::new(2..4, 1)];
+        let resolved = style_runs(6, 2, &spans);
+        assert_eq!(objects(&resolved), vec![0..2, 4..6]);
+        assert_eq!(resolved.len(), 3);
+    }
+
+    #[test]
+    fn edit_next_to_inline_object() {
+        let before = style_runs(6, 1, &[object(2..3)]);
+        // Text inserted after an object doesn't become part of it.
+        let after = edit_style_runs(&before, 3..3, 2);
+        assert_eq!(objects(&after), vec![2..3]);
+        assert_eq!(runs(&after), vec![(0..2, 0), (2..3, 0), (3..8, 0)]);
+        // Deleting the object's text removes it.
+        assert_eq!(objects(&edit_style_runs(&before, 2..3, 0)), vec![]);
+    }
+
+    fn summary(session: &LayoutSession<String>) -> Summary {
+        session
+            .iter_all()
+            .map(|run| {
+                let glyphs = run
+                    .glyphs()
+                    .map(|glyph| {
+                        let offset = glyph.offset * 100.0;
+                        let offset = (offset.x().round() as i32, offset.y().round() as i32);
+                        (glyph.glyph_id, glyph.cluster, offset)
+                    })
+                    .collect();
+                (run.text_range(), run.bidi_level(), glyphs)
+            })
+            .collect()
+    }
+
+    // Check that editing gives the same layout as creating a session with
+    // the edited text.
+    fn check_edit(collection: &FontCollection, text: &str, range: Range<usize>, replacement: &str) {
+        let style = TextStyle::new(16.0);
+        check_styled_edit(collection, &style, text, range, replacement, &style);
+    }
+
+    // Like `check_edit`, where the edit is expected to change the style of
+    // the session to `new_style`.
+    fn check_styled_edit(
+        collection: &FontCollection,
+        style: &TextStyle,
+        text: &str,
+        range: Range<usize>,
+        replacement: &str,
+        new_style: &TextStyle,
+    ) {
+        let mut session = LayoutSession::create(text.to_string(), style, collection);
+        session.edit(range.clone(), replacement, collection);
+        let mut new_text = text.to_string();
+        new_text.replace_range(range.clone(), replacement);
+        let fresh = LayoutSession::create(new_text.clone(), new_style, collection);
+        assert_eq!(session.style().features, new_style.features);
+        assert_eq!(session.text(), new_text);
+        assert_eq!(
+            summary(&session),
+            summary(&fresh),
+            "replacing {:?} in {:?} with {:?}",
+            range,
+            text,
+            replacement
+        );
+    }
+
+    #[test]
+    fn edit_ltr() {
+        let collection = collection();
+        check_edit(&collection, "hello world", 5..5, ",");
+        check_edit(&collection, "hello world", 0..5, "goodbye");
+        check_edit(&collection, "hello world", 6..11, "");
+        check_edit(&collection, "", 0..0, "abc");
+        check_edit(&collection, "abc", 0..3, "");
+    }
+
+    #[test]
+    fn edit_rtl() {
+        let collection = collection();
+        check_edit(&collection, "שלום עולם", 8..8, "ים");
+        check_edit(&collection, "abc שלום def", 4..12, "");
+        check_edit(&collection, "abc def", 4..4, "שלום ");
+        check_edit(&collection, "abc 123 def", 0..3, "שלום");
+    }
+
+    #[test]
+    fn edit_joining_and_ligatures() {
+        let collection = collection();
+        // Arabic letters change form when joined to the inserted letter.
+        check_edit(&collection, "سلام عليكم", 4..4, "س");
+        check_edit(&collection, "سلام عليكم", 2..6, "");
+        check_edit(&collection, "office", 2..2, "f");
+        check_edit(&collection, "offce", 3..3, "i");
+        check_edit(&collection, "e\u{301}a", 1..1, "\u{302}");
+    }
+
+    #[test]
+    fn edit_paragraphs() {
+        let collection = collection();
+        check_edit(&collection, "abc سلام\nعليكم def", 0..3, "שלום");
+        check_edit(&collection, "abc\ndef", 3..4, "");
+        check_edit(&collection, "abc def", 3..3, "\n");
+        check_edit(&collection, "שלום\nabc\nעולם", 9..12, "סלאם");
+        check_edit(&collection, "a\n\nb", 2..2, "x");
+    }
+
+    #[test]
+    fn edit_random() {
+        let collection = collection();
+        let style = TextStyle::new(16.0);
+        let pieces = [
+            "a", "b ", "fi", " ", "ש", "ל", "س", "ل", "ا", "1", "\n", "\u{301}", ".",
+        ];
+        // A simple deterministic generator, to avoid a dependency.
+        let mut state = 0x2545_f491_u32;
+        let mut next = |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as usize % n
+        };
+        let mut text = String::new();
+        for _ in 0..40 {
+            text.push_str(pieces[next(pieces.len())]);
+        }
+        // Edits are applied one after another to the same session.
+        let mut session = LayoutSession::create(text.clone(), &style, &collection);
+        for _ in 0..200 {
+            let boundaries: Vec<usize> = (0..=text.len())
+                .filter(|&i| text.is_char_boundary(i))
+                .collect();
+            let a = boundaries[next(boundaries.len())];
+            let b = boundaries[next(boundaries.len())];
+            let range = a.min(b)..a.max(b);
+            let mut replacement = String::new();
+            for _ in 0..next(4) {
+                replacement.push_str(pieces[next(pieces.len())]);
+            }
+            session.edit(range.clone(), &replacement, &collection);
+            text.replace_range(range, &replacement);
+            let fresh = LayoutSession::create(text.clone(), &style, &collection);
+            assert_eq!(summary(&session), summary(&fresh), "editing to {:?}", text);
+        }
+    }
+}
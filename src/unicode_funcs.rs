@@ -20,10 +20,7 @@ use crate::tables::{
 };
 
 fn make_unicode_funcs() -> *mut hb_unicode_funcs_t {
-    unsafe {
-        let funcs_ptr = hb_unicode_funcs_create(null_mut());
-        funcs_ptr
-    }
+    unsafe { hb_unicode_funcs_create(null_mut()) }
 }
 
 struct Funcs(*mut hb_unicode_funcs_t);
@@ -97,7 +94,7 @@ unsafe extern "C" fn unicode_decompose(
     b: *mut hb_codepoint_t,
     _user_data: *mut c_void,
 ) -> hb_bool_t {
-    if ab >= HANGUL_SYL_BASE && ab < HANGUL_SYL_BASE + HANGUL_SYL_COUNT {
+    if (HANGUL_SYL_BASE..HANGUL_SYL_BASE + HANGUL_SYL_COUNT).contains(&ab) {
         // Decompose Hangul algorithmically.
         let syl = ab - HANGUL_SYL_BASE;
         let t = syl % HANGUL_T_COUNT;
@@ -112,7 +109,7 @@ unsafe extern "C" fn unicode_decompose(
         }
         return true.into();
     }
-    if let Ok(ix) = CANONICAL_DECOMP_KEY.binary_search(&ab.into()) {
+    if let Ok(ix) = CANONICAL_DECOMP_KEY.binary_search(&ab) {
         let (a_result, b_result) = CANONICAL_DECOMP_VAL[ix];
         *a = a_result;
         *b = b_result;